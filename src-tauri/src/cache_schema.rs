@@ -0,0 +1,104 @@
+//! Versions of the cache layout and the migrations between them
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use crate::{library_store::StoreError, url_extension, DEFAULT_PROFILE};
+
+/// Version of the layout `CachData` is written with
+pub const CACHE_VERSION: u32 = 1;
+
+/// `MIGRATIONS[i]` turns a cache of version `i` into one of version `i + 1`
+const MIGRATIONS: [fn(&mut Map<String, Value>); CACHE_VERSION as usize] = [v0_to_v1];
+
+#[derive(Error, Debug, Serialize, Clone, PartialEq, Eq)]
+pub enum CacheError {
+    #[error("The library was saved by a newer version of the app (cache version {0})")]
+    Newer(u32),
+
+    #[error("The library could not be read: {0}")]
+    Unreadable(String),
+
+    #[error("The library database could not be opened: {0}")]
+    Library(String),
+}
+
+impl From<StoreError> for CacheError {
+    fn from(e: StoreError) -> Self {
+        Self::Library(e.to_string())
+    }
+}
+
+/// Brings a cache of any older version to `CACHE_VERSION`
+pub fn migrate(cache: &mut Value) -> Result<(), CacheError> {
+    let cache = cache
+        .as_object_mut()
+        .ok_or_else(|| CacheError::Unreadable("the cache is not an object".to_owned()))?;
+    let version = match cache.get("version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| CacheError::Unreadable(format!("invalid version {version}")))?,
+    };
+    if version > CACHE_VERSION {
+        return Err(CacheError::Newer(version));
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(cache);
+    }
+    cache.insert("version".to_owned(), CACHE_VERSION.into());
+    Ok(())
+}
+
+/// Whether `key` of `object` is a non-empty string
+fn has_string(object: &Map<String, Value>, key: &str) -> bool {
+    object
+        .get(key)
+        .and_then(Value::as_str)
+        .map_or(false, |value| !value.is_empty())
+}
+
+/// Caches before versioning
+/// They had a single cursor, and depending on their age no failed downloads
+/// and wallpapers without `post_name` (one image per post), `profile` and `format`
+fn v0_to_v1(cache: &mut Map<String, Value>) {
+    cache
+        .entry("failed_downloads")
+        .or_insert_with(|| Value::Array(Vec::new()));
+    let last_seen = cache.remove("last_seen_wallpaper");
+    let cursors = cache
+        .entry("last_seen_wallpapers")
+        .or_insert_with(|| Value::Object(Map::new()));
+    if let (Some(Value::String(last_seen)), Some(cursors)) = (last_seen, cursors.as_object_mut()) {
+        if !last_seen.is_empty() && !cursors.contains_key(DEFAULT_PROFILE) {
+            cursors.insert(DEFAULT_PROFILE.to_owned(), last_seen.into());
+        }
+    }
+
+    let wallpapers = cache.get_mut("posts").and_then(Value::as_array_mut);
+    let wallpapers = wallpapers
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_object_mut);
+    for wallpaper in wallpapers {
+        if !has_string(wallpaper, "post_name") {
+            let name = wallpaper.get("name").cloned().unwrap_or_default();
+            wallpaper.insert("post_name".to_owned(), name);
+        }
+        if !has_string(wallpaper, "profile") {
+            wallpaper.insert("profile".to_owned(), DEFAULT_PROFILE.into());
+        }
+        // the extension was the format before formats were detected
+        if !has_string(wallpaper, "format") {
+            let format = wallpaper
+                .get("file_name")
+                .and_then(Value::as_str)
+                .and_then(url_extension)
+                .unwrap_or_default()
+                .to_owned();
+            wallpaper.insert("format".to_owned(), format.into());
+        }
+    }
+}
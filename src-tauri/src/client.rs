@@ -1,171 +1,754 @@
 use futures_util::{future::join_all, StreamExt};
 use log::{debug, info, warn};
-use reqwest::{Client, ClientBuilder};
-use serde::Serialize;
+use rand::Rng;
+use reqwest::{
+    header::{HeaderMap, CONTENT_RANGE, RANGE},
+    Client, ClientBuilder, RequestBuilder, Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
+    io,
     path::PathBuf,
     sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use thiserror::Error;
 use tokio::{
     self,
-    fs::{create_dir_all, File},
-    io::AsyncWriteExt,
+    fs::{create_dir_all, metadata, remove_file, rename, File, OpenOptions},
+    io::{AsyncReadExt, AsyncWriteExt},
 };
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    string_serializer, Config, Post, TokenInfo, UserData, WallpaperError, VALID_EXTENSION,
+    download_queue::DownloadQueue,
+    image_extension,
+    library_store::StoreError,
+    listing::{Link, Listing, ListingData, Thing},
+    progress::{FetchEvent, Progress, DOWNLOAD_EVENT_INTERVAL},
+    string_serializer, Config, Credentials, Image, Post, TokenInfo, UserData, WallpaperError,
+    VALID_EXTENSION,
 };
 
+/// Request a new token this long before reddit would reject the current one
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+struct AccessToken {
+    value: String,
+    expires_at: Instant,
+}
+
+impl AccessToken {
+    fn is_expired(&self) -> bool {
+        Instant::now() + TOKEN_EXPIRY_MARGIN >= self.expires_at
+    }
+}
+
+/// Most fullnames `/api/info` accepts in one request
+const INFO_BATCH_SIZE: usize = 100;
+
+/// Bytes the longest signature `image_extension` sniffs for needs
+const SNIFF_LEN: usize = 12;
+
+/// How often a request is retried after a 429 or a server error
+const MAX_RETRIES: u32 = 5;
+
+/// Upper bound for the exponential backoff between retries
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The request budget reddit reported in its last response
+/// https://github.com/reddit-archive/reddit/wiki/API#rules
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RateBudget {
+    /// Requests left in the current window
+    pub remaining: Option<f32>,
+    /// Seconds until the window resets
+    pub reset_in: Option<u64>,
+}
+
+#[derive(Default)]
+struct RateState {
+    remaining: Option<f32>,
+    reset_at: Option<Instant>,
+}
+
+/// Sends all requests of the app
+/// Requests to reddit wait when its rate-limit budget is used up, all are retried
+/// with jittered exponential backoff on 429 and server errors
+pub struct RequestScheduler {
+    client: Client,
+    state: Mutex<RateState>,
+}
+
+impl RequestScheduler {
+    pub fn new() -> Self {
+        let client = ClientBuilder::new()
+            .user_agent("Wallpaper downloader")
+            .pool_idle_timeout(None)
+            .build()
+            .expect("failed to initialize http client");
+        Self {
+            client,
+            state: Default::default(),
+        }
+    }
+
+    /// Current budget as reported by reddit
+    pub fn budget(&self) -> RateBudget {
+        let state = self.state.lock().unwrap();
+        RateBudget {
+            remaining: state.remaining,
+            reset_in: state
+                .reset_at
+                .map(|reset_at| reset_at.saturating_duration_since(Instant::now()).as_secs()),
+        }
+    }
+
+    /// Sends the request to reddit built by `build` once the budget allows it
+    /// `build` is called again for every retry
+    pub async fn send(
+        &self,
+        build: impl Fn(&Client) -> RequestBuilder,
+    ) -> Result<Response, ClientError> {
+        self.send_with(build, true).await
+    }
+
+    /// Sends a request to another host, like an image download,
+    /// which isn't counted against reddit's budget but still retried
+    pub async fn send_unmetered(
+        &self,
+        build: impl Fn(&Client) -> RequestBuilder,
+    ) -> Result<Response, ClientError> {
+        self.send_with(build, false).await
+    }
+
+    async fn send_with(
+        &self,
+        build: impl Fn(&Client) -> RequestBuilder,
+        metered: bool,
+    ) -> Result<Response, ClientError> {
+        let mut attempt = 0;
+        loop {
+            if metered {
+                self.take_budget().await;
+            }
+            let response = build(&self.client).send().await?;
+            if metered {
+                self.update(response.headers());
+            }
+
+            let status = response.status();
+            let retryable = status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+            if !retryable || attempt >= MAX_RETRIES {
+                return Ok(response);
+            }
+
+            let delay = retry_after(response.headers()).unwrap_or_else(|| backoff(attempt));
+            warn!(
+                "request to {} failed with {status}, retrying in {:.1}s",
+                response.url(),
+                delay.as_secs_f32()
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Takes one request from the budget, sleeping until the current window resets
+    /// if no requests are left
+    /// Requests that run at the same time each take their own share,
+    /// the headers of their responses correct the estimate afterwards
+    async fn take_budget(&self) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                match (state.remaining, state.reset_at) {
+                    (Some(remaining), Some(reset_at)) if remaining < 1. => {
+                        match reset_at.checked_duration_since(Instant::now()) {
+                            Some(wait) => wait,
+                            // the window reset, the next response reports the new budget
+                            None => {
+                                state.remaining = None;
+                                return;
+                            }
+                        }
+                    }
+                    (Some(remaining), _) => {
+                        state.remaining = Some(remaining - 1.);
+                        return;
+                    }
+                    (None, _) => return,
+                }
+            };
+            info!("rate limit reached, waiting {}s", wait.as_secs());
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Reads the rate-limit headers reddit sends with every api response
+    fn update(&self, headers: &HeaderMap) {
+        let remaining = header_value::<f32>(headers, "x-ratelimit-remaining");
+        let reset = header_value::<u64>(headers, "x-ratelimit-reset");
+        if remaining.is_none() && reset.is_none() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.remaining = remaining;
+        state.reset_at = reset.map(|reset| Instant::now() + Duration::from_secs(reset));
+        debug!("rate limit: {remaining:?} requests left, reset in {reset:?}s");
+    }
+}
+
+fn header_value<T: std::str::FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    header_value::<u64>(headers, "retry-after").map(Duration::from_secs)
+}
+
+impl Default for RequestScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Exponential backoff with up to 50% random jitter
+fn backoff(attempt: u32) -> Duration {
+    let base = Duration::from_secs(1 << attempt.min(6)).min(MAX_BACKOFF);
+    base + base.mul_f32(rand::thread_rng().gen_range(0.0..0.5))
+}
+
 pub struct RedditClient {
-    client: Arc<Client>,
-    token: String,
-    base_path: PathBuf,
+    scheduler: Arc<RequestScheduler>,
+    /// Shared by all downloads of the client, so fetches that run at the same time
+    /// stay within the `DownloadLimits` together
+    queue: Arc<DownloadQueue>,
+    token: tokio::sync::Mutex<AccessToken>,
+    /// Name of the logged in user
     username: String,
+    config: Config,
+    progress: Progress,
 }
 
-#[derive(Error, Debug, Serialize)]
+/// Cloneable so that callers of a coalesced fetch can all receive its result
+#[derive(Error, Debug, Serialize, Clone)]
 pub enum ClientError {
     #[error("Bad credentials")]
     BadCredetials,
 
-    #[error(transparent)]
+    #[error("Reddit rejected the access token")]
+    TokenExpired,
+
+    #[error("Access to the resource is forbidden")]
+    Forbidden,
+
+    #[error("Request failed with status {0}")]
+    Http(u16),
+
+    #[error("Reddit returned a malformed listing: {0}")]
+    MalformedListing(String),
+
+    #[error("Reddit returned a malformed response: {0}")]
+    MalformedResponse(String),
+
+    #[error("Authorization failed: {0}")]
+    AuthorizationFailed(String),
+
+    #[error("The fetch was cancelled")]
+    Cancelled,
+
+    #[error("Unable to save the library: {0}")]
+    Persistence(String),
+
+    #[error("Unable to read the library: {0}")]
+    Library(String),
+
+    #[error("Download ended after {received} of {expected} bytes")]
+    IncompleteDownload { expected: u64, received: u64 },
+
+    #[error("Network error: {0}")]
     #[serde(with = "string_serializer")]
-    Reqwest(#[from] reqwest::Error),
+    Network(Arc<reqwest::Error>),
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Network(Arc::new(e))
+    }
+}
+
+impl From<StoreError> for ClientError {
+    fn from(e: StoreError) -> Self {
+        Self::Library(e.to_string())
+    }
+}
+
+/// Listings the client can read posts from
+/// https://www.reddit.com/dev/api#section_listings
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ListingSource {
+    /// Posts the user saved
+    Saved,
+    /// Posts the user upvoted
+    Upvoted,
+    Subreddit {
+        name: String,
+        sort: Sort,
+    },
+    /// A multireddit `user` created
+    Multireddit {
+        user: String,
+        name: String,
+        sort: Sort,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "by", content = "t", rename_all = "snake_case")]
+pub enum Sort {
+    Hot,
+    New,
+    Top(TimeRange),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeRange {
+    Hour,
+    Day,
+    Week,
+    Month,
+    Year,
+    All,
+}
+
+impl TimeRange {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TimeRange::Hour => "hour",
+            TimeRange::Day => "day",
+            TimeRange::Week => "week",
+            TimeRange::Month => "month",
+            TimeRange::Year => "year",
+            TimeRange::All => "all",
+        }
+    }
+}
+
+impl Sort {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Sort::Hot => "hot",
+            Sort::New => "new",
+            Sort::Top(_) => "top",
+        }
+    }
+}
+
+impl ListingSource {
+    /// Api path of the listing, `username` is the logged in user
+    fn path(&self, username: &str) -> String {
+        match self {
+            ListingSource::Saved => format!("user/{username}/saved"),
+            ListingSource::Upvoted => format!("user/{username}/upvoted"),
+            ListingSource::Subreddit { name, sort } => format!("r/{name}/{}", sort.as_str()),
+            ListingSource::Multireddit { user, name, sort } => {
+                format!("user/{user}/m/{name}/{}", sort.as_str())
+            }
+        }
+    }
+
+    fn query(&self) -> Vec<(&'static str, String)> {
+        match self {
+            ListingSource::Subreddit {
+                sort: Sort::Top(range),
+                ..
+            }
+            | ListingSource::Multireddit {
+                sort: Sort::Top(range),
+                ..
+            } => vec![("t", range.as_str().to_owned())],
+            _ => vec![],
+        }
+    }
+}
+
+/// One page of a listing
+#[derive(Serialize, Debug)]
+pub struct ListingPage {
+    pub posts: Vec<Post>,
+    /// Cursor for the next page, `None` on the last page
+    pub after: Option<String>,
+}
+
+/// Maps the links among `things` to Post-objects
+fn into_posts(things: impl IntoIterator<Item = Thing<Link>>) -> impl Iterator<Item = Post> {
+    things
+        .into_iter()
+        .filter_map(|thing| match Post::try_from(thing) {
+            Ok(post) => Some(post),
+            Err(e) => {
+                debug!("skipping listing item: {e}");
+                None
+            }
+        })
+}
+
+/// Turns error status codes into the matching `ClientError`
+pub(crate) fn check_status(response: Response) -> Result<Response, ClientError> {
+    match response.status() {
+        status if status.is_success() => Ok(response),
+        StatusCode::UNAUTHORIZED => Err(ClientError::TokenExpired),
+        StatusCode::FORBIDDEN => Err(ClientError::Forbidden),
+        status => Err(ClientError::Http(status.as_u16())),
+    }
+}
+
+/// Asks for the bytes from `offset` on
+fn with_range(request: RequestBuilder, offset: u64) -> RequestBuilder {
+    if offset > 0 {
+        request.header(RANGE, format!("bytes={offset}-"))
+    } else {
+        request
+    }
+}
+
+/// First byte of a partial response, from `content-range: bytes {start}-{end}/{size}`
+fn content_range_start(response: &Response) -> Option<u64> {
+    response
+        .headers()
+        .get(CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes ")?
+        .split('-')
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// An image that was not added to the library
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FailedDownload {
+    /// The post without its images
+    pub post: Post,
+    pub image: Image,
+    /// Why the image was not added
+    pub error: String,
+    /// How often the download failed
+    #[serde(default)]
+    pub attempts: u32,
+    /// The image was skipped or its content is no usable image,
+    /// so it is only downloaded again on request
+    #[serde(default)]
+    pub rejected: bool,
+    /// Unix timestamp of the last failure
+    #[serde(default)]
+    pub failed_at: u64,
+}
+
+impl FailedDownload {
+    pub fn new(post: &Post, image: Image, error: String, rejected: bool) -> Self {
+        Self {
+            post: Post {
+                images: vec![],
+                ..post.clone()
+            },
+            image,
+            error,
+            attempts: 1,
+            rejected,
+            failed_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+        }
+    }
+}
+
+/// Result of `downloader_post_images`
+#[derive(Default, Debug)]
+pub struct Downloads {
+    /// Maps image-names to the file names
+    pub paths: HashMap<String, String>,
+    pub failed: Vec<FailedDownload>,
 }
 
 async fn get_and_add_to_map(
     post: Arc<Post>,
-    map: Arc<Mutex<HashMap<String, String>>>,
+    image: Image,
+    downloads: Arc<Mutex<Downloads>>,
     client: &RedditClient,
+    queue: Arc<DownloadQueue>,
+    cancel: CancellationToken,
 ) {
+    let name = image.name.clone();
+    let _slot = tokio::select! {
+        slot = queue.acquire(&image.url) => slot,
+        _ = cancel.cancelled() => return,
+    };
     let response = client
         .download_post_image(
-            client.base_path.clone(),
+            client.config.path.clone(),
             post.clone(),
-            client.client.clone(),
+            image.clone(),
+            client.scheduler.clone(),
+            queue.clone(),
+            cancel,
         )
         .await;
 
-    if let Ok(path_buf) = response {
-        map.lock().unwrap().insert(post.name.clone(), path_buf);
-    } else {
-        warn!("wallpaper error: {:?}", response);
+    match response {
+        Ok(path_buf) => {
+            downloads.lock().unwrap().paths.insert(name, path_buf);
+        }
+        Err(WallpaperError::Client(ClientError::Cancelled)) => {
+            debug!("download of {name} was cancelled")
+        }
+        Err(e) => {
+            warn!("wallpaper error: {:?}", e);
+            let rejected = matches!(
+                e,
+                WallpaperError::NotAnImage(_) | WallpaperError::InvalidEnding
+            );
+            downloads.lock().unwrap().failed.push(FailedDownload::new(
+                &post,
+                image,
+                e.to_string(),
+                rejected,
+            ));
+        }
     }
 }
 
 impl RedditClient {
-    pub async fn new(config: &Config) -> Result<Self, ClientError> {
-        let client = Arc::new(
-            ClientBuilder::new()
-                .user_agent("Wallpaper downloader")
-                .pool_idle_timeout(None)
-                .build()
-                .unwrap(),
-        );
-
-        let token = Self::get_token(&client, config).await?;
-        Ok(Self {
-            client,
-            token,
-            base_path: config.path.clone(),
-            username: config.username.clone(),
-        })
+    /// Create a new client that sends its requests through `scheduler`
+    pub async fn new(
+        config: &Config,
+        scheduler: Arc<RequestScheduler>,
+    ) -> Result<Self, ClientError> {
+        let token = Self::get_token(&scheduler, config).await?;
+        let mut client = Self {
+            scheduler,
+            queue: Arc::new(DownloadQueue::new(&config.downloads)),
+            token: tokio::sync::Mutex::new(token),
+            username: config.account.username.clone(),
+            config: config.clone(),
+            progress: Default::default(),
+        };
+        // the authorization flow doesn't tell us who logged in
+        if config.has_refresh_token() {
+            client.username = client.fetch_userdata().await?.name;
+        }
+        Ok(client)
     }
 
-    async fn get_token(client: &Client, config: &Config) -> Result<String, ClientError> {
+    /// Reports fetched pages and downloads to the subscribers of `progress`
+    pub fn with_progress(mut self, progress: Progress) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// The request budget reddit reported last
+    pub fn rate_budget(&self) -> RateBudget {
+        self.scheduler.budget()
+    }
+
+    async fn get_token(
+        scheduler: &RequestScheduler,
+        config: &Config,
+    ) -> Result<AccessToken, ClientError> {
         let mut map = HashMap::new();
-        map.insert("grant_type", "password");
-        map.insert("username", &config.username);
-        map.insert("password", &config.password);
+        match config.credentials() {
+            Credentials::Password { username, password } => {
+                map.insert("grant_type", "password");
+                map.insert("username", username);
+                map.insert("password", password);
+            }
+            Credentials::RefreshToken(refresh_token) => {
+                map.insert("grant_type", "refresh_token");
+                map.insert("refresh_token", refresh_token);
+            }
+        }
 
         let form_data = map.iter().collect::<Vec<(_, _)>>();
 
-        let resp = client
-            .post("https://www.reddit.com/api/v1/access_token")
-            .basic_auth(&config.client_id, Some(&config.client_secret))
-            .form(&form_data);
+        let url = config.endpoints.access_token_url();
+        let resp = scheduler
+            .send(|client| {
+                client
+                    .post(&url)
+                    .basic_auth(
+                        &config.account.client_id,
+                        Some(&config.account.client_secret),
+                    )
+                    .form(&form_data)
+            })
+            .await?;
+        // reddit answers an invalid refresh token with 400
+        if resp.status() == StatusCode::UNAUTHORIZED || resp.status() == StatusCode::BAD_REQUEST {
+            return Err(ClientError::BadCredetials);
+        }
+        let resp = check_status(resp)?;
+        let t: TokenInfo =
+            serde_json::from_str(&resp.text().await?).map_err(|_| ClientError::BadCredetials)?;
+        debug!("received access token valid for {}s", t.expires_in);
+        Ok(AccessToken {
+            value: t.access_token,
+            expires_at: Instant::now() + Duration::from_secs(t.expires_in),
+        })
+    }
 
-        let resp = resp.send().await.unwrap();
-        let t: TokenInfo = serde_json::from_str(&resp.text().await.unwrap())
-            .map_err(|_| ClientError::BadCredetials)?;
-        Ok(t.access_token)
+    /// Returns the current access token and requests a new one if it is about to expire
+    async fn access_token(&self) -> Result<String, ClientError> {
+        let mut token = self.token.lock().await;
+        if token.is_expired() {
+            info!("access token expired, requesting a new one");
+            *token = Self::get_token(&self.scheduler, &self.config).await?;
+        }
+        Ok(token.value.clone())
     }
 
-    async fn create_request_with_auth(&self, url: &str, root: &str) -> reqwest::RequestBuilder {
-        self.client
-            .get(String::from(root) + url)
-            .header("Authorization", format!("bearer {}", self.token))
+    /// Replaces the token reddit rejected with a new one
+    /// Does nothing if another request already replaced it in the meantime
+    async fn refresh_token(&self, rejected: &str) -> Result<String, ClientError> {
+        let mut token = self.token.lock().await;
+        if token.value == rejected {
+            info!("access token was rejected, requesting a new one");
+            *token = Self::get_token(&self.scheduler, &self.config).await?;
+        }
+        Ok(token.value.clone())
     }
 
-    pub async fn fetch_userdata(&self) -> UserData {
-        let response = self
-            .create_request_with_auth("/me", "https://oauth.reddit.com/api/v1")
-            .await
-            .send()
+    /// Sends an authorized GET-request
+    /// If reddit answers with 401 the token is refreshed and the request is sent once more
+    async fn get_with_auth(
+        &self,
+        url: &str,
+        query: &[(&str, String)],
+    ) -> Result<Response, ClientError> {
+        let token = self.access_token().await?;
+        let response = self.send_with_token(url, query, &token).await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return check_status(response);
+        }
+        let token = self.refresh_token(&token).await?;
+        check_status(self.send_with_token(url, query, &token).await?)
+    }
+
+    async fn send_with_token(
+        &self,
+        url: &str,
+        query: &[(&str, String)],
+        token: &str,
+    ) -> Result<Response, ClientError> {
+        self.scheduler
+            .send(|client| {
+                client
+                    .get(url)
+                    .header("Authorization", format!("bearer {}", token))
+                    .query(query)
+            })
             .await
-            .unwrap();
-        serde_json::from_str(&response.text().await.unwrap()).unwrap()
+    }
+
+    pub async fn fetch_userdata(&self) -> Result<UserData, ClientError> {
+        let response = self
+            .get_with_auth(&self.config.endpoints.api_url("api/v1/me"), &[])
+            .await?;
+        serde_json::from_str(&response.text().await?)
+            .map_err(|e| ClientError::MalformedResponse(e.to_string()))
+    }
+
+    /// Fetch one page of `source`, starting after the fullname `after`
+    async fn fetch_listing(
+        &self,
+        source: &ListingSource,
+        after: Option<&str>,
+    ) -> Result<ListingData<Thing<Link>>, ClientError> {
+        let url = self.config.endpoints.api_url(&source.path(&self.username));
+
+        // after is a field accepted by reddit api
+        // https://www.reddit.com/dev/api#listings
+        let mut query = source.query();
+        if let Some(after) = after {
+            query.push(("after", after.to_owned()));
+        }
+
+        debug!("Requesting {url} with after: {after:?}");
+        let response = self.get_with_auth(&url, &query).await?;
+        let listing: Listing<Thing<Link>> = serde_json::from_str(&response.text().await?)
+            .map_err(|e| ClientError::MalformedListing(e.to_string()))?;
+        Ok(listing.data)
+    }
+
+    /// Fetch a single page of posts from `source`
+    pub async fn fetch_page(
+        &self,
+        source: &ListingSource,
+        after: Option<&str>,
+    ) -> Result<ListingPage, ClientError> {
+        let listing = self.fetch_listing(source, after).await?;
+        Ok(ListingPage {
+            posts: into_posts(listing.children).collect(),
+            after: listing.after,
+        })
     }
 
     /// Fetch all saved posts until `until` is found in one of the requests
     /// changes until so that it has the id of the newest saved post after
     /// this method finished executing
-    pub async fn fetch_saved_until(&self, until: &str) -> (Vec<Post>, String) {
-        let mut all_children = vec![];
+    pub async fn fetch_saved_until(
+        &self,
+        until: &str,
+        cancel: &CancellationToken,
+    ) -> Result<(Vec<Post>, String), ClientError> {
+        self.fetch_until(&ListingSource::Saved, until, cancel).await
+    }
 
-        // after is a field accepted by reddit api
-        // https://www.reddit.com/dev/api#listings
+    /// Fetch all posts of `source` until `until` is found in one of the requests
+    /// Also returns the fullname of the newest item to use as next `until`
+    /// Fails with `ClientError::Cancelled` once `cancel` is triggered
+    pub async fn fetch_until(
+        &self,
+        source: &ListingSource,
+        until: &str,
+        cancel: &CancellationToken,
+    ) -> Result<(Vec<Post>, String), ClientError> {
+        let mut all_children = vec![];
         let mut after: Option<String> = None;
 
-        // the function sets the accepted variable `until` to the the newest saved post
-        let mut new_until = Default::default();
+        // the function sets the accepted variable `until` to the the newest post
+        // and keeps it if the listing is empty
+        let mut new_until = until.to_owned();
 
         loop {
-            let form: Vec<(&str, String)> = if after.is_none() {
-                vec![]
-            } else {
-                vec![("after", after.as_ref().unwrap().clone())]
+            let listing = tokio::select! {
+                listing = self.fetch_listing(source, after.as_deref()) => listing?,
+                _ = cancel.cancelled() => return Err(ClientError::Cancelled),
             };
-
-            debug!("Requesting saved posts with after: {:?}", after);
-            let saved = self
-                .create_request_with_auth(
-                    &format!("user/{}/saved", self.username),
-                    &String::from("https://oauth.reddit.com/"),
-                )
-                .await
-                .query(&form)
-                .send()
-                .await
-                .unwrap();
-
-            let content = &saved.text().await.unwrap();
-
-            // std::fs::write("test.json", content).unwrap();
-            let resp = json::parse(content).unwrap();
-            let child_array = &resp["data"]["children"];
+            self.progress.emit(FetchEvent::ListingPage {
+                posts: listing.children.len(),
+                after: listing.after.clone(),
+            });
 
             // in first iteration set the temp variable to update `until`
-            // to the first post in the list, hence the most recent saved one
+            // to the first post in the list, hence the most recent one
             if after.is_none() {
-                new_until = child_array[0]["data"]["name"].to_string();
-            }
-            after = Some(resp["data"]["after"].to_string().trim().to_owned());
-
-            if let Some(next_after) = &after {
-                if next_after.starts_with("null") {
-                    after = None;
+                if let Some(newest) = listing.children.first() {
+                    new_until = newest.data.name.clone();
                 }
             }
+            after = listing.after;
+
             let mut found_last = false;
 
-            // map children-JsonValues to Post-objects
-            // only take as many posts until we found the last seen post
-            let children = child_array.members().map(Post::from).take_while(|post| {
-                if post.name == *until {
-                    debug!("stopping at post {}", post.name);
+            // only take as many things until we found the last seen one
+            let children = listing.children.into_iter().take_while(|thing| {
+                if thing.data.name == *until {
+                    debug!("stopping at post {}", thing.data.name);
                     found_last = true;
                     false
                 } else {
@@ -173,7 +756,7 @@ impl RedditClient {
                 }
             });
 
-            all_children.extend(children);
+            all_children.extend(into_posts(children));
 
             // stop requesting more if we found the last seen post
             // or there are no more posts to be fetched
@@ -182,74 +765,220 @@ impl RedditClient {
             }
         }
         info!("fetched {} posts", all_children.len());
-        (all_children, new_until)
+        Ok((all_children, new_until))
     }
 
     /// gets all posts the user saved
-    pub async fn fetch_all_saved_posts(&self) -> Vec<Post> {
-        self.fetch_saved_until("").await.0
+    pub async fn fetch_all_saved_posts(&self) -> Result<Vec<Post>, ClientError> {
+        Ok(self
+            .fetch_saved_until("", &CancellationToken::new())
+            .await?
+            .0)
     }
 
-    /// returns a a hashmap which maps post-ids to the image-paths
-    pub async fn downloader_post_images(&self, posts: &[Arc<Post>]) -> HashMap<String, String> {
-        let post_to_path = Arc::new(Mutex::new(HashMap::new()));
-        let tasks = posts
-            .iter()
-            .map(|post| get_and_add_to_map(post.clone(), post_to_path.clone(), self));
+    /// Fetch the posts with the fullnames `names` through `/api/info`
+    /// Posts that were deleted or aren't links are missing from the result
+    pub async fn fetch_info(&self, names: &[String]) -> Result<Vec<Post>, ClientError> {
+        let url = self.config.endpoints.api_url("api/info");
+        let mut posts = vec![];
+        for batch in names.chunks(INFO_BATCH_SIZE) {
+            let response = self.get_with_auth(&url, &[("id", batch.join(","))]).await?;
+            let listing: Listing<Thing<Link>> = serde_json::from_str(&response.text().await?)
+                .map_err(|e| ClientError::MalformedListing(e.to_string()))?;
+            posts.extend(into_posts(listing.data.children));
+        }
+        Ok(posts)
+    }
+
+    /// downloads every image of the posts within the `DownloadLimits` of the config,
+    /// which also cover the other downloads of the client that are running
+    /// Downloads that did not finish when `cancel` is triggered are neither
+    /// in the paths nor in the failed downloads
+    pub async fn downloader_post_images(
+        &self,
+        posts: &[Arc<Post>],
+        cancel: &CancellationToken,
+    ) -> Downloads {
+        let downloads = Arc::new(Mutex::new(Downloads::default()));
+        let tasks = posts.iter().flat_map(|post| {
+            let downloads = downloads.clone();
+            let queue = self.queue.clone();
+            post.images.iter().map(move |image| {
+                get_and_add_to_map(
+                    post.clone(),
+                    image.clone(),
+                    downloads.clone(),
+                    self,
+                    queue.clone(),
+                    cancel.clone(),
+                )
+            })
+        });
         join_all(tasks).await;
-        Mutex::into_inner(Arc::try_unwrap(post_to_path).unwrap()).unwrap()
+        Mutex::into_inner(Arc::try_unwrap(downloads).unwrap()).unwrap()
     }
 
-    /// download the image contained in the post
+    /// download one image of the post
     pub async fn download_post_image(
         &self,
         mut path: PathBuf,
         post: Arc<Post>,
-        client: Arc<Client>,
+        image: Image,
+        scheduler: Arc<RequestScheduler>,
+        queue: Arc<DownloadQueue>,
+        cancel: CancellationToken,
     ) -> Result<String, WallpaperError> {
         if !path.is_dir() {
-            create_dir_all(&path).await.unwrap();
+            create_dir_all(&path).await?;
         }
-        path.push(&post.name);
+        let progress = self.progress.clone();
         tokio::spawn(async move {
-            let resp = client
-                .get(post.url.clone())
-                .send()
-                .await
-                .map_err(|e| WallpaperError::Client(ClientError::Reqwest(e)))?;
+            if let Some(file_name) = VALID_EXTENSION
+                .iter()
+                .map(|extension| format!("{}.{extension}", image.name))
+                .find(|file_name| path.join(file_name).exists())
+            {
+                info!("Skipping image {:?} as it's already present", post.title);
+                return Ok(file_name);
+            }
 
-            let extension = resp
+            // the image is downloaded next to the library and only renamed once it's complete,
+            // a part left over by an earlier attempt is resumed
+            let part_path = path.join(format!("{}.part", image.name));
+            let mut offset = metadata(&part_path).await.map_or(0, |meta| meta.len());
+
+            let resp = loop {
+                let resp = tokio::select! {
+                    resp = scheduler.send_unmetered(|client| with_range(client.get(&image.url), offset)) => resp?,
+                    _ = cancel.cancelled() => return Err(ClientError::Cancelled.into()),
+                };
+                // the part is at least as long as the image, start over
+                if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE && offset > 0 {
+                    remove_file(&part_path).await?;
+                    offset = 0;
+                    continue;
+                }
+                break check_status(resp)?;
+            };
+
+            let content_type = resp
                 .headers()
                 .get("content-type")
-                .expect("need content-type")
-                .to_str()
-                .unwrap()
-                .split('/')
-                .nth(1)
-                .unwrap();
-
-            if !VALID_EXTENSION.contains(&extension) {
-                return Err(WallpaperError::InvalidEnding);
+                .and_then(|content_type| content_type.to_str().ok())
+                .map(str::to_owned);
+            let sniff = |head: &[u8]| image_extension(head, content_type.as_deref(), &image.url);
+
+            // servers that don't support ranges send the whole image again
+            if resp.status() == StatusCode::PARTIAL_CONTENT {
+                if content_range_start(&resp) != Some(offset) {
+                    remove_file(&part_path).await?;
+                    return Err(ClientError::MalformedResponse(
+                        "content-range does not match the requested range".to_owned(),
+                    )
+                    .into());
+                }
+                info!("Resuming image {:?} at byte {offset}", post.title);
+            } else {
+                offset = 0;
             }
-            path.set_extension(extension);
-
-            if path.exists() {
-                info!("Skipping image {:?} as it's already present", post.title);
-                return Ok(path.file_name().unwrap().to_str().unwrap().to_owned());
+            info!("Saving image {:?} at {:?}", post.title, part_path);
+
+            let total = resp.content_length().map(|length| offset + length);
+            let report = |downloaded| {
+                progress.emit(FetchEvent::Download {
+                    image: image.name.clone(),
+                    downloaded,
+                    total,
+                })
+            };
+            // the format is decided once the start of the image is long enough
+            // to hold any signature, a resumed part may already contain it
+            let mut head = vec![];
+            let mut extension = None;
+            let mut file = if offset > 0 {
+                File::open(&part_path)
+                    .await?
+                    .take(SNIFF_LEN as u64)
+                    .read_to_end(&mut head)
+                    .await?;
+                if head.len() >= SNIFF_LEN {
+                    extension = Some(sniff(&head)?);
+                }
+                OpenOptions::new().append(true).open(&part_path).await?
+            } else {
+                File::create(&part_path).await?
+            };
+            let mut body_stream = resp.bytes_stream();
+            let mut downloaded = offset;
+            let mut reported = offset;
+            loop {
+                let chunk = tokio::select! {
+                    chunk = body_stream.next() => chunk,
+                    // the part is resumed by the next attempt
+                    _ = cancel.cancelled() => return Err(ClientError::Cancelled.into()),
+                };
+                let chunk = match chunk {
+                    Some(chunk) => chunk.map_err(ClientError::from)?,
+                    None => break,
+                };
+                if extension.is_none() {
+                    head.extend_from_slice(&chunk);
+                    if head.len() >= SNIFF_LEN {
+                        match sniff(&head) {
+                            Ok(sniffed) => extension = Some(sniffed),
+                            Err(e) => {
+                                drop(file);
+                                remove_file(&part_path).await?;
+                                return Err(e);
+                            }
+                        }
+                    }
+                }
+                file.write_all(&chunk).await?;
+                queue.consume(chunk.len()).await;
+                downloaded += chunk.len() as u64;
+                if downloaded - reported >= DOWNLOAD_EVENT_INTERVAL {
+                    report(downloaded);
+                    reported = downloaded;
+                }
             }
-            info!("Saving image {:?} at {:?}", post.title, path);
+            file.sync_all().await?;
+            drop(file);
 
-            let mut file = File::create(&path).await.unwrap();
-            let mut body_stream = resp.bytes_stream();
-            while let Some(chunk) = body_stream.next().await {
-                let chunk = chunk.unwrap();
-                file.write_all(&chunk).await.unwrap();
+            if let Some(expected) = total.filter(|expected| *expected != downloaded) {
+                // a part that is too long can't be resumed
+                if downloaded > expected {
+                    remove_file(&part_path).await?;
+                }
+                return Err(ClientError::IncompleteDownload {
+                    expected,
+                    received: downloaded,
+                }
+                .into());
             }
+            // images shorter than the longest signature
+            let extension = match extension {
+                Some(extension) => extension,
+                None if !head.is_empty() => match sniff(&head) {
+                    Ok(extension) => extension,
+                    Err(e) => {
+                        remove_file(&part_path).await?;
+                        return Err(e);
+                    }
+                },
+                None => {
+                    remove_file(&part_path).await?;
+                    return Err(WallpaperError::NotAnImage("empty response".to_owned()));
+                }
+            };
+            let file_name = format!("{}.{}", image.name, extension);
+            path.push(&file_name);
+            rename(&part_path, &path).await?;
+            report(downloaded);
 
-            // TODO: ugly
-            Ok(path.file_name().unwrap().to_str().unwrap().to_owned())
+            Ok(file_name)
         })
         .await
-        .unwrap()
+        .map_err(|e| WallpaperError::Io(io::Error::other(e)))?
     }
 }
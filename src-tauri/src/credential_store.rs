@@ -0,0 +1,241 @@
+//! Encrypted storage for the secrets of `Config`
+//! The key is either derived from a passphrase of the user
+//! or read from a key file that is created next to the store
+
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use log::info;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+use crate::{string_serializer, Config};
+
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// Everything in `Config` that must not be stored in clear text
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Secrets {
+    #[serde(default)]
+    pub password: String,
+    #[serde(default)]
+    pub client_secret: String,
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    /// Secrets of the inactive profiles by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Secrets>,
+}
+
+impl Secrets {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+#[derive(Error, Debug, Serialize)]
+pub enum CredentialError {
+    #[error("The credentials are protected by a passphrase")]
+    Locked,
+
+    #[error("Wrong passphrase or corrupted credentials")]
+    Decryption,
+
+    #[error("Malformed credential store: {0}")]
+    Malformed(String),
+
+    #[error(transparent)]
+    #[serde(with = "string_serializer")]
+    Io(#[from] io::Error),
+}
+
+/// How the key of the store is obtained
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Kdf {
+    KeyFile,
+    /// Argon2id with default parameters
+    Passphrase {
+        salt: String,
+    },
+}
+
+/// Content of the store file
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    kdf: Kdf,
+    nonce: String,
+    ciphertext: String,
+}
+
+pub struct CredentialStore {
+    path: PathBuf,
+    key_file: PathBuf,
+}
+
+impl CredentialStore {
+    /// Store that keeps its files in `dir`
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            path: dir.join("credentials.json"),
+            key_file: dir.join("credentials.key"),
+        }
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// Whether `load` needs a passphrase
+    pub fn needs_passphrase(&self) -> Result<bool, CredentialError> {
+        Ok(matches!(self.envelope()?.kdf, Kdf::Passphrase { .. }))
+    }
+
+    /// Decrypts the stored secrets
+    /// Returns empty secrets if nothing was stored yet
+    pub fn load(&self, passphrase: Option<&str>) -> Result<Secrets, CredentialError> {
+        if !self.exists() {
+            return Ok(Default::default());
+        }
+        let envelope = self.envelope()?;
+        let key = match (&envelope.kdf, passphrase) {
+            (Kdf::KeyFile, _) => self.machine_key()?,
+            (Kdf::Passphrase { salt }, Some(passphrase)) => derive_key(passphrase, &decode(salt)?)?,
+            (Kdf::Passphrase { .. }, None) => return Err(CredentialError::Locked),
+        };
+        let nonce = decode(&envelope.nonce)?;
+        if nonce.len() != NONCE_LEN {
+            return Err(CredentialError::Malformed("invalid nonce".to_owned()));
+        }
+        let plaintext = XChaCha20Poly1305::new(Key::from_slice(&key))
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                decode(&envelope.ciphertext)?.as_ref(),
+            )
+            .map_err(|_| CredentialError::Decryption)?;
+        serde_json::from_slice(&plaintext).map_err(|e| CredentialError::Malformed(e.to_string()))
+    }
+
+    /// Encrypts `secrets` with a key derived from `passphrase` or with the machine-local key
+    pub fn save(&self, secrets: &Secrets, passphrase: Option<&str>) -> Result<(), CredentialError> {
+        let (kdf, key) = match passphrase {
+            Some(passphrase) => {
+                let mut salt = [0; SALT_LEN];
+                rand::thread_rng().fill_bytes(&mut salt);
+                let key = derive_key(passphrase, &salt)?;
+                let salt = STANDARD.encode(salt);
+                (Kdf::Passphrase { salt }, key)
+            }
+            None => (Kdf::KeyFile, self.machine_key()?),
+        };
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let plaintext = serde_json::to_vec(secrets).expect("secrets are always serializable");
+        let ciphertext = XChaCha20Poly1305::new(Key::from_slice(&key))
+            .encrypt(&nonce, plaintext.as_ref())
+            .map_err(|_| CredentialError::Decryption)?;
+        let envelope = Envelope {
+            kdf,
+            nonce: STANDARD.encode(nonce),
+            ciphertext: STANDARD.encode(ciphertext),
+        };
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_private(
+            &self.path,
+            &serde_json::to_vec(&envelope).expect("envelope is always serializable"),
+        )
+    }
+
+    /// Moves secrets that older versions wrote into the config file into the store
+    /// An existing store is left as it is, secrets in the config file are leftovers then
+    /// Returns whether `config` contained any, in which case the config file has to be rewritten
+    pub fn migrate_plaintext(
+        &self,
+        config: &Config,
+        passphrase: Option<&str>,
+    ) -> Result<bool, CredentialError> {
+        let secrets = config.secrets();
+        if secrets.is_empty() {
+            return Ok(false);
+        }
+        if self.exists() {
+            info!("dropping the secrets left in the config file, the credential store has them");
+        } else {
+            info!("moving secrets from the config file into the credential store");
+            self.save(&secrets, passphrase)?;
+        }
+        Ok(true)
+    }
+
+    fn envelope(&self) -> Result<Envelope, CredentialError> {
+        serde_json::from_slice(&fs::read(&self.path)?)
+            .map_err(|e| CredentialError::Malformed(e.to_string()))
+    }
+
+    /// Reads the key file and creates it on first use
+    fn machine_key(&self) -> Result<[u8; KEY_LEN], CredentialError> {
+        match fs::read(&self.key_file) {
+            Ok(bytes) => bytes
+                .try_into()
+                .map_err(|_| CredentialError::Malformed("invalid key file".to_owned())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let mut key = [0; KEY_LEN];
+                rand::thread_rng().fill_bytes(&mut key);
+                if let Some(parent) = self.key_file.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                write_private(&self.key_file, &key)?;
+                Ok(key)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<[u8; KEY_LEN], CredentialError> {
+    let mut key = [0; KEY_LEN];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| CredentialError::Malformed(e.to_string()))?;
+    Ok(key)
+}
+
+fn decode(data: &str) -> Result<Vec<u8>, CredentialError> {
+    STANDARD
+        .decode(data)
+        .map_err(|e| CredentialError::Malformed(e.to_string()))
+}
+
+/// Writes a file only the current user can read
+/// The data is written to a temporary file first, so a crash never leaves
+/// a half-written file in place of the old one
+fn write_private(path: &Path, data: &[u8]) -> Result<(), CredentialError> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&temp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
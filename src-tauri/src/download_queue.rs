@@ -0,0 +1,104 @@
+//! Limits how many images are downloaded at once and how much bandwidth they use
+
+use reqwest::Url;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{sleep, Instant},
+};
+
+use crate::DownloadLimits;
+
+pub struct DownloadQueue {
+    workers: Arc<Semaphore>,
+    per_host: usize,
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+    throttle: Option<Throttle>,
+}
+
+/// Allows a download to run while it is held
+pub struct Slot {
+    _host: OwnedSemaphorePermit,
+    _worker: OwnedSemaphorePermit,
+}
+
+impl DownloadQueue {
+    pub fn new(limits: &DownloadLimits) -> Self {
+        Self {
+            workers: Arc::new(Semaphore::new(limits.concurrency.max(1))),
+            per_host: limits.per_host.max(1),
+            hosts: Default::default(),
+            throttle: limits.bandwidth.filter(|rate| *rate > 0).map(Throttle::new),
+        }
+    }
+
+    /// Waits until a download from `url` may start
+    pub async fn acquire(&self, url: &str) -> Slot {
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_owned))
+            .unwrap_or_default();
+        let host = self
+            .hosts
+            .lock()
+            .unwrap()
+            .entry(host)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.per_host)))
+            .clone();
+        // the host is waited for first, so a busy host doesn't hold up the others
+        let host = host
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+        let worker = self
+            .workers
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+        Slot {
+            _host: host,
+            _worker: worker,
+        }
+    }
+
+    /// Waits until `bytes` more may be downloaded
+    pub async fn consume(&self, bytes: usize) {
+        if let Some(throttle) = &self.throttle {
+            throttle.consume(bytes as u64).await;
+        }
+    }
+}
+
+/// Spreads downloads so they don't exceed `rate` bytes per second
+struct Throttle {
+    rate: u64,
+    /// When the bandwidth is free again
+    next_free: Mutex<Instant>,
+}
+
+impl Throttle {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    async fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut next_free = self.next_free.lock().unwrap();
+            let now = Instant::now();
+            let start = (*next_free).max(now);
+            *next_free = start + Duration::from_secs_f64(bytes as f64 / self.rate as f64);
+            start - now
+        };
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
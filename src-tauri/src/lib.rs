@@ -1,9 +1,18 @@
 use client::ClientError;
-use json::JsonValue;
+use credential_store::{CredentialError, Secrets};
+use library_store::StoreError;
 use serde::{Deserialize, Serialize};
-use std::{io, path::PathBuf};
+use std::{collections::BTreeMap, io, path::PathBuf};
 use thiserror::Error;
+pub mod cache_schema;
 pub mod client;
+pub mod credential_store;
+pub mod download_queue;
+pub mod library_store;
+pub mod listing;
+pub mod oauth;
+pub mod progress;
+pub mod resolver;
 pub mod string_serializer;
 pub mod wallpaper_manager;
 
@@ -15,13 +24,285 @@ pub struct UserData {
     pub name: String,
 }
 
+/// Name of the profile configs from before profiles existed are loaded as
+pub const DEFAULT_PROFILE: &str = "default";
+
+fn default_profile() -> String {
+    DEFAULT_PROFILE.to_owned()
+}
+
+/// Credentials of one reddit account
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
-pub struct Config {
+pub struct Account {
     username: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     password: String,
-    pub path: PathBuf,
     client_id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     client_secret: String,
+    /// Set by the authorization flow, used instead of `username` and `password`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+}
+
+impl Account {
+    fn secrets(&self) -> Secrets {
+        Secrets {
+            password: self.password.clone(),
+            client_secret: self.client_secret.clone(),
+            refresh_token: self.refresh_token.clone(),
+            profiles: Default::default(),
+        }
+    }
+
+    fn set_secrets(&mut self, secrets: Secrets) {
+        self.password = secrets.password;
+        self.client_secret = secrets.client_secret;
+        self.refresh_token = secrets.refresh_token;
+    }
+}
+
+/// Settings of the app
+/// The account of the active profile is stored inline, the others in `profiles`
+/// The secrets are kept in the `CredentialStore` on disk and only left out
+/// of the config file when they are empty
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Config {
+    #[serde(flatten)]
+    account: Account,
+    pub path: PathBuf,
+    #[serde(default)]
+    pub endpoints: Endpoints,
+    /// Lets the imgur resolver list all images of an album instead of only the cover
+    #[serde(default)]
+    pub imgur_client_id: Option<String>,
+    /// Subreddits whose posts are added to the library
+    #[serde(default)]
+    pub subreddits: Subreddits,
+    /// Ignore `subreddits` and add image posts from any subreddit
+    #[serde(default)]
+    pub any_subreddit: bool,
+    /// Port of the redirect uri registered for the reddit app
+    #[serde(default)]
+    pub redirect_port: Option<u16>,
+    #[serde(default)]
+    pub downloads: DownloadLimits,
+    /// Name of the profile whose account is used
+    #[serde(default = "default_profile")]
+    pub active_profile: String,
+    /// Accounts of the inactive profiles by name
+    #[serde(default)]
+    profiles: BTreeMap<String, Account>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new("", "", "", "", PathBuf::new())
+    }
+}
+
+impl Config {
+    pub fn new(
+        username: &str,
+        password: &str,
+        client_id: &str,
+        client_secret: &str,
+        path: PathBuf,
+    ) -> Self {
+        Self {
+            account: Account {
+                username: username.to_owned(),
+                password: password.to_owned(),
+                client_id: client_id.to_owned(),
+                client_secret: client_secret.to_owned(),
+                refresh_token: None,
+            },
+            path,
+            endpoints: Default::default(),
+            imgur_client_id: None,
+            subreddits: Default::default(),
+            any_subreddit: false,
+            redirect_port: None,
+            downloads: Default::default(),
+            active_profile: default_profile(),
+            profiles: Default::default(),
+        }
+    }
+
+    /// Uses `refresh_token` to log in from now on and forgets the password
+    pub fn set_refresh_token(&mut self, refresh_token: String) {
+        self.account.refresh_token = Some(refresh_token);
+        self.account.password.clear();
+    }
+
+    pub fn has_refresh_token(&self) -> bool {
+        self.account.refresh_token.is_some()
+    }
+
+    /// Secrets of all profiles
+    pub fn secrets(&self) -> Secrets {
+        let mut secrets = self.account.secrets();
+        secrets.profiles = self
+            .profiles
+            .iter()
+            .map(|(name, account)| (name.clone(), account.secrets()))
+            .collect();
+        secrets
+    }
+
+    pub fn set_secrets(&mut self, mut secrets: Secrets) {
+        for (name, account) in &mut self.profiles {
+            account.set_secrets(secrets.profiles.remove(name).unwrap_or_default());
+        }
+        self.account.set_secrets(secrets);
+    }
+
+    /// The config as it is written to the config file
+    pub fn without_secrets(&self) -> Self {
+        let mut config = self.clone();
+        config.set_secrets(Default::default());
+        config
+    }
+
+    /// Names of all profiles, the active one first
+    pub fn profile_names(&self) -> Vec<String> {
+        let mut names = vec![self.active_profile.clone()];
+        names.extend(self.profiles.keys().cloned());
+        names
+    }
+
+    /// Makes the profile `name` the active one
+    pub fn switch_profile(&mut self, name: &str) -> Result<(), WallpaperError> {
+        if name == self.active_profile {
+            return Ok(());
+        }
+        let account = self
+            .profiles
+            .remove(name)
+            .ok_or_else(|| WallpaperError::UnknownProfile(name.to_owned()))?;
+        let previous = std::mem::replace(&mut self.account, account);
+        let previous_name = std::mem::replace(&mut self.active_profile, name.to_owned());
+        self.profiles.insert(previous_name, previous);
+        Ok(())
+    }
+
+    /// Adds a profile without credentials and makes it the active one
+    pub fn add_profile(&mut self, name: &str) -> Result<(), WallpaperError> {
+        let name = name.trim();
+        if name.is_empty() || self.profile_names().iter().any(|profile| profile == name) {
+            return Err(WallpaperError::ProfileExists(name.to_owned()));
+        }
+        self.profiles.insert(name.to_owned(), Default::default());
+        self.switch_profile(name)
+    }
+
+    pub(crate) fn credentials(&self) -> Credentials<'_> {
+        match &self.account.refresh_token {
+            Some(refresh_token) => Credentials::RefreshToken(refresh_token),
+            None => Credentials::Password {
+                username: &self.account.username,
+                password: &self.account.password,
+            },
+        }
+    }
+
+    /// Whether posts of `subreddit` belong in the library
+    pub fn accepts_subreddit(&self, subreddit: &str) -> bool {
+        self.any_subreddit || self.subreddits.contains(subreddit)
+    }
+}
+
+/// How the client logs in to reddit
+pub(crate) enum Credentials<'a> {
+    /// Password grant of script apps
+    Password {
+        username: &'a str,
+        password: &'a str,
+    },
+    /// Obtained through the authorization flow of installed apps
+    RefreshToken(&'a str),
+}
+
+/// Names of subreddits, with or without the leading `r/`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Subreddits(pub Vec<String>);
+
+impl Default for Subreddits {
+    fn default() -> Self {
+        Self(vec!["wallpaper".to_owned()])
+    }
+}
+
+impl Subreddits {
+    /// Reddit treats subreddit names case-insensitively
+    pub fn contains(&self, subreddit: &str) -> bool {
+        self.0.iter().any(|name| {
+            let name = name.trim();
+            let name = name.strip_prefix("r/").unwrap_or(name);
+            name.eq_ignore_ascii_case(subreddit)
+        })
+    }
+}
+
+/// How many images are downloaded at once and how fast
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct DownloadLimits {
+    /// Downloads running at the same time
+    pub concurrency: usize,
+    /// Downloads running at the same time from a single host
+    pub per_host: usize,
+    /// Bytes per second all downloads together may use, unlimited if not set
+    pub bandwidth: Option<u64>,
+}
+
+impl Default for DownloadLimits {
+    fn default() -> Self {
+        Self {
+            concurrency: 8,
+            per_host: 4,
+            bandwidth: None,
+        }
+    }
+}
+
+/// Base urls of the reddit api
+/// Only need to be changed to run the app against a local stand-in server
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Endpoints {
+    /// Serves `/api/v1/access_token`
+    pub auth: String,
+    /// Serves all requests that need an access token
+    pub api: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Self {
+            auth: "https://www.reddit.com".to_owned(),
+            api: "https://oauth.reddit.com".to_owned(),
+        }
+    }
+}
+
+impl Endpoints {
+    pub fn authorize_url(&self) -> String {
+        format!("{}/api/v1/authorize", self.auth.trim_end_matches('/'))
+    }
+
+    pub fn access_token_url(&self) -> String {
+        format!("{}/api/v1/access_token", self.auth.trim_end_matches('/'))
+    }
+
+    /// Joins `path` onto the api base url
+    pub fn api_url(&self, path: &str) -> String {
+        format!(
+            "{}/{}",
+            self.api.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        )
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Eq, Clone)]
@@ -30,6 +311,75 @@ pub struct Post {
     pub title: String,
     pub url: String,
     pub name: String,
+    /// All images of the post, more than one for galleries
+    #[serde(default)]
+    pub images: Vec<Image>,
+}
+
+/// A single downloadable image of a post
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct Image {
+    /// Unique name of the image
+    /// The post's fullname for single-image posts, `{fullname}_{media_id}` for gallery items
+    pub name: String,
+    pub url: String,
+}
+
+/// Whether `url` points to a file with one of the `VALID_EXTENSION`s
+pub fn is_image_url(url: &str) -> bool {
+    url_extension(url).map_or(false, |extension| {
+        VALID_EXTENSION.contains(&extension.to_ascii_lowercase().as_str())
+    })
+}
+
+/// Extension of the file `url` points to, ignoring query and fragment
+pub fn url_extension(url: &str) -> Option<&str> {
+    let path = url.split(['?', '#']).next()?;
+    let file_name = path.rsplit('/').next()?;
+    file_name.rsplit_once('.').map(|(_, extension)| extension)
+}
+
+/// Decides which image format a download has
+/// The magic bytes at the start of `head` win, then the content-type, then the url
+/// Returns the extension the image is saved with
+pub fn image_extension(
+    head: &[u8],
+    content_type: Option<&str>,
+    url: &str,
+) -> Result<String, WallpaperError> {
+    // error pages are often served with status 200
+    let markup = head.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'<');
+    let mime = content_type.map(|content_type| {
+        let mime = content_type.split(';').next().unwrap_or_default();
+        mime.trim().to_ascii_lowercase()
+    });
+    let other_type = mime.as_deref().map_or(false, |mime| {
+        !mime.starts_with("image/") && mime != "application/octet-stream"
+    });
+    if markup || other_type {
+        return Err(WallpaperError::NotAnImage(
+            content_type.unwrap_or("markup").to_owned(),
+        ));
+    }
+
+    if let Ok(format) = image::guess_format(head) {
+        return match format {
+            image::ImageFormat::Jpeg => Ok("jpeg"),
+            image::ImageFormat::Png => Ok("png"),
+            image::ImageFormat::Gif => Ok("gif"),
+            image::ImageFormat::Bmp => Ok("bmp"),
+            image::ImageFormat::Tiff => Ok("tiff"),
+            format => Err(WallpaperError::NotAnImage(format!("{format:?}"))),
+        }
+        .map(str::to_owned);
+    }
+
+    let from_header = mime.as_deref().and_then(|mime| mime.strip_prefix("image/"));
+    from_header
+        .or_else(|| url_extension(url))
+        .map(str::to_ascii_lowercase)
+        .filter(|extension| VALID_EXTENSION.contains(&extension.as_str()))
+        .ok_or(WallpaperError::InvalidEnding)
 }
 
 impl PartialEq for Post {
@@ -44,20 +394,13 @@ impl std::hash::Hash for Post {
     }
 }
 
-impl From<&JsonValue> for Post {
-    fn from(jv: &JsonValue) -> Self {
-        Self {
-            subreddit: jv["data"]["subreddit"].to_string(),
-            title: jv["data"]["title"].to_string(),
-            url: jv["data"]["url"].to_string(),
-            name: jv["data"]["name"].to_string(),
-        }
-    }
-}
-
 #[derive(Deserialize)]
 struct TokenInfo {
     access_token: String,
+    /// lifetime of the token in seconds
+    expires_in: u64,
+    /// only sent for the authorization-code grant with permanent duration
+    refresh_token: Option<String>,
 }
 
 #[derive(Error, Debug, Serialize)]
@@ -68,9 +411,30 @@ pub enum WallpaperError {
     #[error("No Root Paths")]
     NoRootPaths,
 
+    #[error("The download is not an image we can use: {0}")]
+    NotAnImage(String),
+
+    #[error("There is no profile named {0}")]
+    UnknownProfile(String),
+
+    #[error("A profile named {0:?} exists already")]
+    ProfileExists(String),
+
+    #[error("There is no wallpaper named {0}")]
+    UnknownWallpaper(String),
+
+    #[error("The library database is not available")]
+    NoLibrary,
+
+    #[error(transparent)]
+    Library(#[from] StoreError),
+
     #[error(transparent)]
     Client(#[from] ClientError),
 
+    #[error(transparent)]
+    Credentials(#[from] CredentialError),
+
     #[error(transparent)]
     #[serde(with = "string_serializer")]
     Io(#[from] io::Error),
@@ -0,0 +1,393 @@
+//! Storage of the library
+//! Replaces the cache file, which had to be written in full on every change
+
+use rusqlite::{params, Connection, OptionalExtension, Row, Transaction};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fs::create_dir_all,
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use thiserror::Error;
+
+use crate::{
+    client::FailedDownload,
+    string_serializer,
+    wallpaper_manager::{PostInfo, Wallpaper},
+};
+
+/// Version of the database layout, kept in its `user_version`
+const SCHEMA_VERSION: i32 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE wallpapers (
+        name TEXT PRIMARY KEY NOT NULL,
+        post_name TEXT NOT NULL,
+        subreddit TEXT NOT NULL,
+        title TEXT NOT NULL,
+        url TEXT NOT NULL,
+        file_name TEXT NOT NULL,
+        profile TEXT NOT NULL,
+        format TEXT NOT NULL
+    );
+    CREATE INDEX wallpapers_post_name ON wallpapers (post_name);
+    CREATE INDEX wallpapers_subreddit ON wallpapers (subreddit COLLATE NOCASE);
+    CREATE TABLE tags (
+        name TEXT NOT NULL REFERENCES wallpapers (name) ON DELETE CASCADE,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (name, tag)
+    );
+    CREATE INDEX tags_tag ON tags (tag);
+    CREATE TABLE post_info (
+        name TEXT PRIMARY KEY NOT NULL,
+        info TEXT NOT NULL
+    );
+    CREATE TABLE cursors (
+        profile TEXT PRIMARY KEY NOT NULL,
+        post_name TEXT NOT NULL
+    );
+    CREATE TABLE failed_downloads (
+        name TEXT PRIMARY KEY NOT NULL,
+        failure TEXT NOT NULL
+    );
+";
+
+const WALLPAPER_COLUMNS: &str =
+    "name, post_name, subreddit, title, url, file_name, profile, format";
+
+#[derive(Error, Debug, Serialize)]
+pub enum StoreError {
+    #[error("The library was saved by a newer version of the app (schema {0})")]
+    Newer(i32),
+
+    #[error(transparent)]
+    #[serde(with = "string_serializer")]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error(transparent)]
+    #[serde(with = "string_serializer")]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    #[serde(with = "string_serializer")]
+    Io(#[from] io::Error),
+}
+
+/// Everything in the library that is kept in memory,
+/// wallpapers are looked up when they are needed
+#[derive(Default)]
+pub struct Library {
+    pub post_data: HashMap<String, PostInfo>,
+    /// Newest post seen per profile
+    pub cursors: HashMap<String, String>,
+    pub failed_downloads: Vec<FailedDownload>,
+}
+
+/// What changed since the library was saved last
+pub struct LibraryChanges<'a> {
+    /// Wallpapers that were added or changed
+    pub wallpapers: &'a [Arc<Wallpaper>],
+    /// Infos that were added or changed
+    pub post_data: &'a HashMap<String, PostInfo>,
+    /// All cursors, the saved ones that differ are replaced and those missing deleted
+    pub cursors: &'a HashMap<String, String>,
+    /// All failed downloads, the saved ones that differ are replaced and those missing deleted
+    pub failed_downloads: &'a [FailedDownload],
+}
+
+pub trait LibraryStore: Send + Sync {
+    /// Reads everything but the wallpapers
+    fn load(&self) -> Result<Library, StoreError>;
+
+    /// All wallpapers in the order they were added
+    fn wallpapers(&self) -> Result<Vec<Wallpaper>, StoreError>;
+
+    /// Whether nothing was saved yet
+    fn wallpapers(&self) -> Result<Vec<Wallpaper>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(&format!(
+            "SELECT {WALLPAPER_COLUMNS} FROM wallpapers ORDER BY rowid"
+        ))?;
+        let wallpapers = statement
+            .query_map([], wallpaper_from_row)?
+            .collect::<Result<_, _>>()?;
+        Ok(wallpapers)
+    }
+
+    fn is_empty(&self) -> Result<bool, StoreError>;
+
+    /// Saves `changes` at once, or nothing of them if it fails
+    fn save(&self, changes: &LibraryChanges) -> Result<(), StoreError>;
+
+    fn wallpaper(&self, name: &str) -> Result<Option<Wallpaper>, StoreError>;
+
+    /// Whether an image of the post `post_name` is in the library
+    fn has_post(&self, post_name: &str) -> Result<bool, StoreError>;
+
+    /// Wallpapers from `subreddit`, compared case-insensitively
+    fn wallpapers_of_subreddit(&self, subreddit: &str) -> Result<Vec<Wallpaper>, StoreError>;
+
+    fn wallpapers_tagged(&self, tag: &str) -> Result<Vec<Wallpaper>, StoreError>;
+
+    fn tags(&self, name: &str) -> Result<Vec<String>, StoreError>;
+
+    /// Replaces the tags of the wallpaper `name`
+    fn set_tags(&self, name: &str, tags: &[String]) -> Result<(), StoreError>;
+}
+
+/// Keeps the library in an SQLite database
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Opens the database at `path`, creating it if it doesn't exist
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    /// A database that only lives as long as the store
+    pub fn in_memory() -> Result<Self, StoreError> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(mut connection: Connection) -> Result<Self, StoreError> {
+        connection.pragma_update(None, "foreign_keys", true)?;
+        let version: i32 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version > SCHEMA_VERSION {
+            return Err(StoreError::Newer(version));
+        }
+        if version < SCHEMA_VERSION {
+            let transaction = connection.transaction()?;
+            transaction.execute_batch(SCHEMA)?;
+            transaction.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+            transaction.commit()?;
+        }
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn query_wallpapers(&self, condition: &str, param: &str) -> Result<Vec<Wallpaper>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(&format!(
+            "SELECT {WALLPAPER_COLUMNS} FROM wallpapers WHERE {condition} ORDER BY rowid"
+        ))?;
+        let wallpapers = statement
+            .query_map([param], wallpaper_from_row)?
+            .collect::<Result<_, _>>()?;
+        Ok(wallpapers)
+    }
+}
+
+impl LibraryStore for SqliteStore {
+    fn load(&self) -> Result<Library, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let post_data = connection
+            .prepare("SELECT name, info FROM post_info")?
+            .query_map([], |row| Ok((row.get(0)?, row.get::<_, String>(1)?)))?
+            .map(|row| {
+                let (name, info) = row?;
+                Ok((name, serde_json::from_str(&info)?))
+            })
+            .collect::<Result<_, StoreError>>()?;
+        let cursors = connection
+            .prepare("SELECT profile, post_name FROM cursors")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        let failed_downloads = connection
+            .prepare("SELECT failure FROM failed_downloads ORDER BY rowid")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .map(|failure| Ok(serde_json::from_str(&failure?)?))
+            .collect::<Result<_, StoreError>>()?;
+        Ok(Library {
+            post_data,
+            cursors,
+            failed_downloads,
+        })
+    }
+
+    fn wallpapers(&self) -> Result<Vec<Wallpaper>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(&format!(
+            "SELECT {WALLPAPER_COLUMNS} FROM wallpapers ORDER BY rowid"
+        ))?;
+        let wallpapers = statement
+            .query_map([], wallpaper_from_row)?
+            .collect::<Result<_, _>>()?;
+        Ok(wallpapers)
+    }
+
+    fn is_empty(&self) -> Result<bool, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let empty = connection.query_row(
+            "SELECT NOT EXISTS (SELECT 1 FROM wallpapers)
+                AND NOT EXISTS (SELECT 1 FROM cursors)
+                AND NOT EXISTS (SELECT 1 FROM failed_downloads)",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(empty)
+    }
+
+    fn save(&self, changes: &LibraryChanges) -> Result<(), StoreError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        save_changes(&transaction, changes)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn wallpaper(&self, name: &str) -> Result<Option<Wallpaper>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let wallpaper = connection
+            .query_row(
+                &format!("SELECT {WALLPAPER_COLUMNS} FROM wallpapers WHERE name = ?1"),
+                [name],
+                wallpaper_from_row,
+            )
+            .optional()?;
+        Ok(wallpaper)
+    }
+
+    fn has_post(&self, post_name: &str) -> Result<bool, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare_cached("SELECT EXISTS (SELECT 1 FROM wallpapers WHERE post_name = ?1)")?;
+        Ok(statement.query_row([post_name], |row| row.get(0))?)
+    }
+
+    fn wallpapers_of_subreddit(&self, subreddit: &str) -> Result<Vec<Wallpaper>, StoreError> {
+        self.query_wallpapers("subreddit = ?1 COLLATE NOCASE", subreddit)
+    }
+
+    fn wallpapers_tagged(&self, tag: &str) -> Result<Vec<Wallpaper>, StoreError> {
+        self.query_wallpapers("name IN (SELECT name FROM tags WHERE tag = ?1)", tag)
+    }
+
+    fn tags(&self, name: &str) -> Result<Vec<String>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement =
+            connection.prepare_cached("SELECT tag FROM tags WHERE name = ?1 ORDER BY tag")?;
+        let tags = statement
+            .query_map([name], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(tags)
+    }
+
+    fn set_tags(&self, name: &str, tags: &[String]) -> Result<(), StoreError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM tags WHERE name = ?1", [name])?;
+        for tag in tags {
+            transaction.execute(
+                "INSERT OR IGNORE INTO tags (name, tag) VALUES (?1, ?2)",
+                params![name, tag],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+fn save_changes(transaction: &Transaction, changes: &LibraryChanges) -> Result<(), StoreError> {
+    // an upsert keeps the rowid, and with it the position in the library
+    let mut statement = transaction.prepare_cached(&format!(
+        "INSERT INTO wallpapers ({WALLPAPER_COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+            ON CONFLICT (name) DO UPDATE SET
+                post_name = excluded.post_name, subreddit = excluded.subreddit,
+                title = excluded.title, url = excluded.url, file_name = excluded.file_name,
+                profile = excluded.profile, format = excluded.format"
+    ))?;
+    for wallpaper in changes.wallpapers {
+        statement.execute(params![
+            wallpaper.name,
+            wallpaper.post_name,
+            wallpaper.subreddit,
+            wallpaper.title,
+            wallpaper.url,
+            wallpaper.file_name,
+            wallpaper.profile,
+            wallpaper.format,
+        ])?;
+    }
+
+    let mut statement = transaction
+        .prepare_cached("INSERT OR REPLACE INTO post_info (name, info) VALUES (?1, ?2)")?;
+    for (name, info) in changes.post_data {
+        statement.execute([name, &serde_json::to_string(info)?])?;
+    }
+
+    // cursors and failed downloads are few, only those that differ from the saved ones are written
+    let cursors = changes
+        .cursors
+        .iter()
+        .map(|(profile, post_name)| (profile.clone(), post_name.clone()))
+        .collect();
+    replace_rows(transaction, "cursors", "profile", "post_name", cursors)?;
+
+    let failed_downloads = changes
+        .failed_downloads
+        .iter()
+        .map(|failed| Ok((failed.image.name.clone(), serde_json::to_string(failed)?)))
+        .collect::<Result<_, StoreError>>()?;
+    replace_rows(
+        transaction,
+        "failed_downloads",
+        "name",
+        "failure",
+        failed_downloads,
+    )
+}
+
+/// Makes the rows of `table` equal to `rows`, which map `key` to `value`
+/// Rows that are unchanged aren't written, changed ones keep their position
+fn replace_rows(
+    transaction: &Transaction,
+    table: &str,
+    key: &str,
+    value: &str,
+    rows: HashMap<String, String>,
+) -> Result<(), StoreError> {
+    let saved = transaction
+        .prepare_cached(&format!("SELECT {key}, {value} FROM {table}"))?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<HashMap<String, String>, _>>()?;
+
+    let mut delete =
+        transaction.prepare_cached(&format!("DELETE FROM {table} WHERE {key} = ?1"))?;
+    for saved_key in saved
+        .keys()
+        .filter(|saved_key| !rows.contains_key(*saved_key))
+    {
+        delete.execute([saved_key])?;
+    }
+    let mut upsert = transaction.prepare_cached(&format!(
+        "INSERT INTO {table} ({key}, {value}) VALUES (?1, ?2)
+            ON CONFLICT ({key}) DO UPDATE SET {value} = excluded.{value}"
+    ))?;
+    for (row_key, row_value) in &rows {
+        if saved.get(row_key) != Some(row_value) {
+            upsert.execute([row_key, row_value])?;
+        }
+    }
+    Ok(())
+}
+
+fn wallpaper_from_row(row: &Row) -> rusqlite::Result<Wallpaper> {
+    Ok(Wallpaper {
+        name: row.get(0)?,
+        post_name: row.get(1)?,
+        subreddit: row.get(2)?,
+        title: row.get(3)?,
+        url: row.get(4)?,
+        file_name: row.get(5)?,
+        profile: row.get(6)?,
+        format: row.get(7)?,
+    })
+}
@@ -0,0 +1,152 @@
+//! Serde models for reddit listings
+//! https://www.reddit.com/dev/api#listings
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use thiserror::Error;
+
+use crate::{Image, Post};
+
+#[derive(Deserialize, Debug)]
+pub struct Listing<T> {
+    pub data: ListingData<T>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ListingData<T> {
+    /// Cursor for the next page, `None` on the last page
+    pub after: Option<String>,
+    pub children: Vec<T>,
+}
+
+/// A reddit object together with its kind (`t1` comment, `t3` link, ...)
+#[derive(Deserialize, Debug)]
+pub struct Thing<T> {
+    pub kind: String,
+    pub data: T,
+}
+
+/// The fields of a link the app cares about
+/// Everything but the fullname can be missing, e.g. for comments or deleted posts
+#[derive(Deserialize, Debug, Default)]
+pub struct Link {
+    /// Fullname like `t3_abc123`
+    pub name: String,
+    pub subreddit: Option<String>,
+    pub title: Option<String>,
+    pub url: Option<String>,
+    /// Set when the post was removed by its author or a moderator
+    pub removed_by_category: Option<String>,
+    pub is_gallery: Option<bool>,
+    /// Order of the gallery items
+    pub gallery_data: Option<GalleryData>,
+    /// Maps the media ids of gallery items to their sources
+    pub media_metadata: Option<HashMap<String, MediaMetadata>>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct GalleryData {
+    pub items: Vec<GalleryItem>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GalleryItem {
+    pub media_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MediaMetadata {
+    /// `valid` once reddit has processed the upload
+    pub status: Option<String>,
+    /// The source image, missing for videos
+    pub s: Option<MediaSource>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MediaSource {
+    /// Html-escaped url of the image, missing for animated media
+    pub u: Option<String>,
+}
+
+impl Link {
+    /// Images of a gallery in display order, skipping items that are not (yet) images
+    fn gallery_images(&self) -> Vec<Image> {
+        let (gallery_data, media_metadata) = match (&self.gallery_data, &self.media_metadata) {
+            (Some(gallery_data), Some(media_metadata)) => (gallery_data, media_metadata),
+            _ => return vec![],
+        };
+        gallery_data
+            .items
+            .iter()
+            .filter_map(|item| {
+                let media = media_metadata.get(&item.media_id)?;
+                if media.status.as_deref() != Some("valid") {
+                    return None;
+                }
+                let url = media.s.as_ref()?.u.as_ref()?;
+                Some(Image {
+                    name: format!("{}_{}", self.name, item.media_id),
+                    url: url.replace("&amp;", "&"),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Why a saved thing could not be turned into a `Post`
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum NotAPost {
+    #[error("{name} is of kind {kind}, not a link")]
+    WrongKind { name: String, kind: String },
+
+    #[error("{0} was deleted")]
+    Deleted(String),
+
+    #[error("{0} has no url")]
+    MissingUrl(String),
+
+    #[error("{0} has no subreddit")]
+    MissingSubreddit(String),
+
+    #[error("gallery {0} contains no images")]
+    EmptyGallery(String),
+}
+
+pub const LINK_KIND: &str = "t3";
+
+impl TryFrom<Thing<Link>> for Post {
+    type Error = NotAPost;
+
+    fn try_from(thing: Thing<Link>) -> Result<Self, Self::Error> {
+        let link = thing.data;
+        if thing.kind != LINK_KIND {
+            return Err(NotAPost::WrongKind {
+                name: link.name,
+                kind: thing.kind,
+            });
+        }
+        if link.removed_by_category.is_some() {
+            return Err(NotAPost::Deleted(link.name));
+        }
+        let images = match (&link.url, link.is_gallery) {
+            (_, Some(true)) => link.gallery_images(),
+            (Some(url), _) => vec![Image {
+                name: link.name.clone(),
+                url: url.clone(),
+            }],
+            (None, _) => vec![],
+        };
+        match (link.url, link.subreddit) {
+            (None, _) => Err(NotAPost::MissingUrl(link.name)),
+            (_, None) => Err(NotAPost::MissingSubreddit(link.name)),
+            _ if images.is_empty() => Err(NotAPost::EmptyGallery(link.name)),
+            (Some(url), Some(subreddit)) => Ok(Post {
+                subreddit,
+                title: link.title.unwrap_or_default(),
+                url,
+                name: link.name,
+                images,
+            }),
+        }
+    }
+}
@@ -5,13 +5,15 @@
 
 use log::warn;
 use reddit_wallpapers::{
-    client::ClientError,
-    wallpaper_manager::{Wallpaper, WallpaperManager},
+    cache_schema::CacheError,
+    client::{ClientError, FailedDownload, ListingPage, ListingSource, RateBudget},
+    wallpaper_manager::{Wallpaper, WallpaperManager, AUTOSAVE_DELAY},
     Config, Post, WallpaperError,
 };
 use std::sync::Arc;
 use tauri::{generate_context, Manager};
 use tauri_plugin_positioner::{Position, WindowExt};
+use tokio::sync::broadcast::error::RecvError;
 
 #[tauri::command]
 async fn get_all_wallpapers(
@@ -24,13 +26,8 @@ async fn get_all_wallpapers(
 #[tauri::command]
 async fn get_cached_wallpapers(
     wm: tauri::State<'_, Arc<WallpaperManager>>,
-) -> Result<Vec<Wallpaper>, ()> {
-    let mut posts = wm
-        .get_cached_wallpapers()
-        .await
-        .iter()
-        .map(|post_arc| (**post_arc).clone())
-        .collect::<Vec<_>>();
+) -> Result<Vec<Wallpaper>, WallpaperError> {
+    let mut posts = wm.get_cached_wallpapers().await?;
     posts.reverse();
     Ok(posts)
 }
@@ -40,13 +37,86 @@ async fn fetch_recent(wm: tauri::State<'_, Arc<WallpaperManager>>) -> Result<(),
     wm.fetch_recent_wallpapers().await
 }
 
+#[tauri::command]
+fn cancel_fetch(wm: tauri::State<'_, Arc<WallpaperManager>>) {
+    wm.cancel_fetch()
+}
+
+#[tauri::command]
+async fn rescan_library(
+    wm: tauri::State<'_, Arc<WallpaperManager>>,
+    refetch_info: bool,
+) -> Result<usize, WallpaperError> {
+    wm.rescan_library(refetch_info).await
+}
+
+#[tauri::command]
+fn get_cache_error(wm: tauri::State<'_, Arc<WallpaperManager>>) -> Option<CacheError> {
+    wm.cache_error()
+}
+
+#[tauri::command]
+fn list_failed_downloads(wm: tauri::State<'_, Arc<WallpaperManager>>) -> Vec<FailedDownload> {
+    wm.failed_downloads()
+}
+
+#[tauri::command]
+async fn retry_failed_downloads(
+    wm: tauri::State<'_, Arc<WallpaperManager>>,
+    names: Vec<String>,
+) -> Result<(), ClientError> {
+    wm.retry_failed_downloads(&names).await
+}
+
+#[tauri::command]
+async fn fetch_candidates(
+    wm: tauri::State<'_, Arc<WallpaperManager>>,
+    source: ListingSource,
+    after: Option<String>,
+) -> Result<ListingPage, ClientError> {
+    wm.fetch_candidates(&source, after.as_deref()).await
+}
+
 #[tauri::command]
 async fn select_wallpaper(
     wm: tauri::State<'_, Arc<WallpaperManager>>,
     name: String,
-) -> Result<(), ()> {
-    wm.set_wallpaper(&name).await;
-    Ok(())
+) -> Result<(), WallpaperError> {
+    wm.set_wallpaper(&name).await
+}
+
+#[tauri::command]
+fn get_wallpapers_of_subreddit(
+    wm: tauri::State<'_, Arc<WallpaperManager>>,
+    subreddit: String,
+) -> Result<Vec<Wallpaper>, WallpaperError> {
+    wm.wallpapers_of_subreddit(&subreddit)
+        .map_err(WallpaperError::from)
+}
+
+#[tauri::command]
+fn get_wallpapers_tagged(
+    wm: tauri::State<'_, Arc<WallpaperManager>>,
+    tag: String,
+) -> Result<Vec<Wallpaper>, WallpaperError> {
+    wm.wallpapers_tagged(&tag)
+}
+
+#[tauri::command]
+fn get_wallpaper_tags(
+    wm: tauri::State<'_, Arc<WallpaperManager>>,
+    name: String,
+) -> Result<Vec<String>, WallpaperError> {
+    wm.tags(&name)
+}
+
+#[tauri::command]
+fn set_wallpaper_tags(
+    wm: tauri::State<'_, Arc<WallpaperManager>>,
+    name: String,
+    tags: Vec<String>,
+) -> Result<(), WallpaperError> {
+    wm.set_tags(&name, &tags)
 }
 
 #[tauri::command]
@@ -67,6 +137,65 @@ async fn set_config(
     wm.set_config(new_config).await
 }
 
+#[tauri::command]
+async fn authorize(
+    app: tauri::AppHandle,
+    wm: tauri::State<'_, Arc<WallpaperManager>>,
+    new_config: Config,
+) -> Result<(), WallpaperError> {
+    wm.authorize(new_config, |url| {
+        tauri::api::shell::open(&app.shell_scope(), url, None).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+fn credentials_locked(wm: tauri::State<'_, Arc<WallpaperManager>>) -> bool {
+    wm.credentials_locked()
+}
+
+#[tauri::command]
+async fn unlock_credentials(
+    wm: tauri::State<'_, Arc<WallpaperManager>>,
+    passphrase: String,
+) -> Result<(), WallpaperError> {
+    wm.unlock_credentials(passphrase).await
+}
+
+#[tauri::command]
+fn set_credentials_passphrase(
+    wm: tauri::State<'_, Arc<WallpaperManager>>,
+    passphrase: Option<String>,
+) -> Result<(), WallpaperError> {
+    wm.set_credentials_passphrase(passphrase)
+}
+
+#[tauri::command]
+fn list_profiles(wm: tauri::State<'_, Arc<WallpaperManager>>) -> Vec<String> {
+    wm.profiles()
+}
+
+#[tauri::command]
+fn add_profile(
+    wm: tauri::State<'_, Arc<WallpaperManager>>,
+    name: String,
+) -> Result<(), WallpaperError> {
+    wm.add_profile(&name)
+}
+
+#[tauri::command]
+async fn switch_profile(
+    wm: tauri::State<'_, Arc<WallpaperManager>>,
+    name: String,
+) -> Result<(), WallpaperError> {
+    wm.switch_profile(&name).await
+}
+
+#[tauri::command]
+fn get_rate_budget(wm: tauri::State<'_, Arc<WallpaperManager>>) -> RateBudget {
+    wm.rate_budget()
+}
+
 #[tauri::command]
 fn is_configured(wm: tauri::State<'_, Arc<WallpaperManager>>) -> bool {
     wm.is_configured()
@@ -78,22 +207,62 @@ async fn main() -> anyhow::Result<()> {
 
     let wm = Arc::new(WallpaperManager::new().await);
     let wm_clone = wm.clone();
+    let mut events = wm.subscribe();
+    let wm_autosave = wm.clone();
+    tauri::async_runtime::spawn(async move { wm_autosave.autosave(AUTOSAVE_DELAY).await });
     let app = tauri::Builder::default()
         .plugin(tauri_plugin_positioner::init())
         .manage(wm)
         .invoke_handler(tauri::generate_handler![
             get_all_wallpapers,
             get_cached_wallpapers,
+            get_cache_error,
+            rescan_library,
             select_wallpaper,
+            get_wallpapers_of_subreddit,
+            get_wallpapers_tagged,
+            get_wallpaper_tags,
+            set_wallpaper_tags,
             fetch_recent,
+            cancel_fetch,
+            list_failed_downloads,
+            retry_failed_downloads,
+            fetch_candidates,
             get_wallpapers_path,
             get_config,
             set_config,
-            is_configured
+            authorize,
+            credentials_locked,
+            unlock_credentials,
+            set_credentials_passphrase,
+            is_configured,
+            get_rate_budget,
+            list_profiles,
+            add_profile,
+            switch_profile
         ])
         .setup(|app| {
             let win = app.get_window("main").unwrap();
             let _ = win.move_window(Position::TopRight);
+
+            // forward the progress of fetches to the frontend
+            let handle = app.handle();
+            tauri::async_runtime::spawn(async move {
+                loop {
+                    match events.recv().await {
+                        Ok(event) => {
+                            handle
+                                .emit_all("fetch-progress", event)
+                                .map_err(|e| warn!("{e}"))
+                                .ok();
+                        }
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!("dropped {skipped} progress events")
+                        }
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+            });
             Ok(())
         })
         .build(generate_context!())
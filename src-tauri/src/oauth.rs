@@ -0,0 +1,196 @@
+//! Authorization-code flow with PKCE for installed apps
+//! https://github.com/reddit-archive/reddit/wiki/OAuth2
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use log::{debug, info};
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, fmt::Display, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    client::{check_status, ClientError, RequestScheduler},
+    Config, TokenInfo,
+};
+
+/// Redirect port used when the config doesn't set one
+pub const DEFAULT_REDIRECT_PORT: u16 = 65010;
+
+const CALLBACK_PATH: &str = "/authorize_callback";
+
+const SCOPES: &str = "identity history read mysubreddits";
+
+/// How long the user has to confirm the authorization in the browser
+const AUTHORIZATION_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Proof key for the code exchange
+/// https://www.rfc-editor.org/rfc/rfc7636
+pub struct Pkce {
+    pub verifier: String,
+    pub challenge: String,
+}
+
+impl Pkce {
+    pub fn new() -> Self {
+        Self::from_verifier(random_string(64))
+    }
+
+    pub fn from_verifier(verifier: String) -> Self {
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+        Self {
+            verifier,
+            challenge,
+        }
+    }
+}
+
+impl Default for Pkce {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn failed(e: impl Display) -> ClientError {
+    ClientError::AuthorizationFailed(e.to_string())
+}
+
+/// Lets the user authorize the app in the browser and returns the refresh token
+/// `open` is called with the url the user has to visit
+pub async fn authorize(
+    config: &Config,
+    scheduler: &RequestScheduler,
+    open: impl FnOnce(&str) -> Result<(), String>,
+) -> Result<String, ClientError> {
+    let port = config.redirect_port.unwrap_or(DEFAULT_REDIRECT_PORT);
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .await
+        .map_err(failed)?;
+    let port = listener.local_addr().map_err(failed)?.port();
+    let redirect_uri = format!("http://127.0.0.1:{port}{CALLBACK_PATH}");
+
+    let state = random_string(32);
+    let pkce = Pkce::new();
+    let url = Url::parse_with_params(
+        &config.endpoints.authorize_url(),
+        &[
+            ("client_id", config.account.client_id.as_str()),
+            ("response_type", "code"),
+            ("state", state.as_str()),
+            ("redirect_uri", redirect_uri.as_str()),
+            ("duration", "permanent"),
+            ("scope", SCOPES),
+            ("code_challenge", pkce.challenge.as_str()),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(failed)?;
+
+    info!("waiting for authorization on {redirect_uri}");
+    open(url.as_str()).map_err(ClientError::AuthorizationFailed)?;
+    let code = tokio::time::timeout(AUTHORIZATION_TIMEOUT, wait_for_code(&listener, &state))
+        .await
+        .map_err(|_| failed("timed out waiting for the browser"))??;
+
+    exchange_code(config, scheduler, &code, &redirect_uri, &pkce.verifier).await
+}
+
+/// Answers requests to the redirect uri until reddit sends the user back
+async fn wait_for_code(listener: &TcpListener, state: &str) -> Result<String, ClientError> {
+    loop {
+        let (mut stream, _) = listener.accept().await.map_err(failed)?;
+        let mut buf = vec![0; 8192];
+        let len = stream.read(&mut buf).await.map_err(failed)?;
+        let request = String::from_utf8_lossy(&buf[..len]);
+
+        // request line looks like `GET /authorize_callback?state=..&code=.. HTTP/1.1`
+        let target = request
+            .lines()
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .unwrap_or_default();
+        let url = match Url::parse(&format!("http://127.0.0.1{target}")) {
+            Ok(url) if url.path() == CALLBACK_PATH => url,
+            // e.g. the browser asking for a favicon
+            _ => {
+                respond(&mut stream, "404 Not Found", "").await;
+                continue;
+            }
+        };
+
+        let params = url.query_pairs().into_owned().collect::<HashMap<_, _>>();
+        let result = if params.get("state").map(String::as_str) != Some(state) {
+            Err(failed("the redirect does not belong to this login"))
+        } else if let Some(error) = params.get("error") {
+            Err(failed(format!("reddit answered with {error}")))
+        } else {
+            params
+                .get("code")
+                .cloned()
+                .ok_or_else(|| failed("reddit sent no code"))
+        };
+
+        let message = match &result {
+            Ok(_) => "Logged in, you can close this window now.".to_owned(),
+            Err(e) => e.to_string(),
+        };
+        respond(&mut stream, "200 OK", &message).await;
+        return result;
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, message: &str) {
+    let body = format!("<!doctype html><html><body><p>{message}</p></body></html>");
+    let response = format!(
+        "HTTP/1.1 {status}\r\ncontent-type: text/html; charset=utf-8\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        debug!("unable to answer the redirect: {e}");
+    }
+}
+
+/// Trades the authorization code for a refresh token
+async fn exchange_code(
+    config: &Config,
+    scheduler: &RequestScheduler,
+    code: &str,
+    redirect_uri: &str,
+    verifier: &str,
+) -> Result<String, ClientError> {
+    let url = config.endpoints.access_token_url();
+    let form = [
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", redirect_uri),
+        ("code_verifier", verifier),
+    ];
+    let response = scheduler
+        .send(|client| {
+            client
+                .post(&url)
+                .basic_auth(
+                    &config.account.client_id,
+                    Some(&config.account.client_secret),
+                )
+                .form(&form)
+        })
+        .await
+        .and_then(check_status)?;
+    let token: TokenInfo = serde_json::from_str(&response.text().await?)
+        .map_err(|e| ClientError::MalformedResponse(e.to_string()))?;
+    token
+        .refresh_token
+        .ok_or_else(|| failed("reddit sent no refresh token"))
+}
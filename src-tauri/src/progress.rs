@@ -0,0 +1,64 @@
+//! Events that report the progress of a fetch
+//! The app forwards them to the frontend, other consumers can subscribe as well
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Events a subscriber can fall behind before it misses some
+const CAPACITY: usize = 256;
+
+/// Bytes downloaded between two `Download` events of the same image
+pub const DOWNLOAD_EVENT_INTERVAL: u64 = 256 * 1024;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FetchEvent {
+    /// A page of the listing was fetched
+    ListingPage { posts: usize, after: Option<String> },
+    /// A post passed the filters and its images will be downloaded
+    PostQueued {
+        name: String,
+        title: String,
+        images: usize,
+    },
+    /// Progress of one image, `total` is unknown without a content-length
+    Download {
+        image: String,
+        downloaded: u64,
+        total: Option<u64>,
+    },
+    /// The thumbnail of an image was written
+    Thumbnail { image: String },
+    /// The fetch is done
+    Finished {
+        posts: usize,
+        images: usize,
+        failed: usize,
+    },
+}
+
+/// Sends `FetchEvent`s to all subscribers
+#[derive(Clone)]
+pub struct Progress {
+    sender: broadcast::Sender<FetchEvent>,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+        }
+    }
+}
+
+impl Progress {
+    pub fn emit(&self, event: FetchEvent) {
+        // nobody listening is fine
+        self.sender.send(event).ok();
+    }
+
+    /// Receives all events emitted from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<FetchEvent> {
+        self.sender.subscribe()
+    }
+}
@@ -0,0 +1,244 @@
+//! Turns links to image hosting pages into direct image urls
+//! Runs between fetching the listing and downloading the images
+
+use futures_util::future::BoxFuture;
+use log::{debug, warn};
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::{
+    client::{check_status, ClientError, RequestScheduler},
+    Config, Image, Post,
+};
+
+/// Resolves the urls of one image host
+pub trait Resolver: Send + Sync {
+    /// Whether `url` points to a page this resolver understands
+    fn handles(&self, url: &Url) -> bool;
+
+    /// Direct image urls of the page `url` points to
+    /// An empty list means the page contains no images
+    fn resolve<'a>(
+        &'a self,
+        url: &'a Url,
+        scheduler: &'a RequestScheduler,
+    ) -> BoxFuture<'a, Result<Vec<String>, ClientError>>;
+}
+
+/// All resolvers the app knows, the first one that handles a url is used
+pub struct Resolvers(Vec<Box<dyn Resolver>>);
+
+impl Resolvers {
+    pub fn new(config: &Config) -> Self {
+        let mut resolvers = Self(vec![]);
+        resolvers.push(RedditPreview);
+        resolvers.push(RedditMedia);
+        resolvers.push(Imgur::new(config.imgur_client_id.clone()));
+        resolvers
+    }
+
+    pub fn push(&mut self, resolver: impl Resolver + 'static) {
+        self.0.push(Box::new(resolver));
+    }
+
+    /// Replaces the images of `post` that point to known hosts with direct image urls
+    /// Images that can't be resolved are kept as they are
+    pub async fn resolve(&self, post: &mut Post, scheduler: &RequestScheduler) {
+        let mut images = vec![];
+        for image in std::mem::take(&mut post.images) {
+            images.extend(self.resolve_image(image, scheduler).await);
+        }
+        post.images = images;
+    }
+
+    async fn resolve_image(&self, image: Image, scheduler: &RequestScheduler) -> Vec<Image> {
+        let url = match Url::parse(&image.url) {
+            Ok(url) => url,
+            Err(_) => return vec![image],
+        };
+        let resolver = match self.0.iter().find(|resolver| resolver.handles(&url)) {
+            Some(resolver) => resolver,
+            None => return vec![image],
+        };
+        match resolver.resolve(&url, scheduler).await {
+            Ok(mut urls) if urls.len() == 1 => {
+                debug!("resolved {} to {}", image.url, urls[0]);
+                vec![Image {
+                    url: urls.remove(0),
+                    ..image
+                }]
+            }
+            Ok(urls) if urls.is_empty() => {
+                warn!("{} contains no images", image.url);
+                vec![image]
+            }
+            // albums get one image per item, named like gallery items
+            Ok(urls) => urls
+                .into_iter()
+                .enumerate()
+                .map(|(i, url)| Image {
+                    name: format!("{}_{i}", image.name),
+                    url,
+                })
+                .collect(),
+            Err(e) => {
+                warn!("unable to resolve {}: {e}", image.url);
+                vec![image]
+            }
+        }
+    }
+}
+
+/// `preview.redd.it/abc.jpg?width=640&...` is a scaled copy of `i.redd.it/abc.jpg`
+pub struct RedditPreview;
+
+impl Resolver for RedditPreview {
+    fn handles(&self, url: &Url) -> bool {
+        url.host_str() == Some("preview.redd.it")
+    }
+
+    fn resolve<'a>(
+        &'a self,
+        url: &'a Url,
+        _scheduler: &'a RequestScheduler,
+    ) -> BoxFuture<'a, Result<Vec<String>, ClientError>> {
+        Box::pin(async move { Ok(vec![format!("https://i.redd.it{}", url.path())]) })
+    }
+}
+
+/// `reddit.com/media?url=...` wraps the actual image url
+pub struct RedditMedia;
+
+impl Resolver for RedditMedia {
+    fn handles(&self, url: &Url) -> bool {
+        matches!(url.host_str(), Some("www.reddit.com" | "reddit.com")) && url.path() == "/media"
+    }
+
+    fn resolve<'a>(
+        &'a self,
+        url: &'a Url,
+        _scheduler: &'a RequestScheduler,
+    ) -> BoxFuture<'a, Result<Vec<String>, ClientError>> {
+        Box::pin(async move {
+            Ok(url
+                .query_pairs()
+                .find(|(key, _)| key == "url")
+                .map(|(_, target)| target.into_owned())
+                .into_iter()
+                .collect())
+        })
+    }
+}
+
+/// Single images (`imgur.com/xyz`) and albums (`imgur.com/a/abc`, `imgur.com/gallery/abc`)
+pub struct Imgur {
+    api: String,
+    /// Needed to list all images of an album, only the cover is used without it
+    client_id: Option<String>,
+}
+
+impl Imgur {
+    pub fn new(client_id: Option<String>) -> Self {
+        Self {
+            api: "https://api.imgur.com".to_owned(),
+            client_id,
+        }
+    }
+
+    async fn album(
+        &self,
+        id: &str,
+        url: &Url,
+        scheduler: &RequestScheduler,
+    ) -> Result<Vec<String>, ClientError> {
+        let client_id = match &self.client_id {
+            Some(client_id) => client_id,
+            None => return self.page(url, scheduler).await,
+        };
+        let api_url = format!("{}/post/v1/albums/{id}", self.api);
+        let response = scheduler
+            .send_unmetered(|client| {
+                client
+                    .get(&api_url)
+                    .query(&[("client_id", client_id.as_str()), ("include", "media")])
+            })
+            .await
+            .and_then(check_status)?;
+        album_images(&response.text().await?)
+            .map_err(|e| ClientError::MalformedResponse(e.to_string()))
+    }
+
+    /// Uses the preview image of the html page
+    async fn page(
+        &self,
+        url: &Url,
+        scheduler: &RequestScheduler,
+    ) -> Result<Vec<String>, ClientError> {
+        let response = scheduler
+            .send_unmetered(|client| client.get(url.clone()))
+            .await
+            .and_then(check_status)?;
+        Ok(og_image(&response.text().await?).into_iter().collect())
+    }
+}
+
+impl Resolver for Imgur {
+    fn handles(&self, url: &Url) -> bool {
+        matches!(
+            url.host_str(),
+            Some("imgur.com" | "www.imgur.com" | "m.imgur.com")
+        )
+    }
+
+    fn resolve<'a>(
+        &'a self,
+        url: &'a Url,
+        scheduler: &'a RequestScheduler,
+    ) -> BoxFuture<'a, Result<Vec<String>, ClientError>> {
+        Box::pin(async move {
+            let segments = url
+                .path_segments()
+                .map(|segments| segments.filter(|s| !s.is_empty()).collect::<Vec<_>>())
+                .unwrap_or_default();
+            match segments.as_slice() {
+                ["a" | "gallery", id] => self.album(id, url, scheduler).await,
+                // links like `imgur.com/xyz.png` only miss the subdomain
+                [file_name] if file_name.contains('.') => {
+                    Ok(vec![format!("https://i.imgur.com/{file_name}")])
+                }
+                [_] => self.page(url, scheduler).await,
+                _ => Ok(vec![]),
+            }
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct ImgurAlbum {
+    media: Vec<ImgurMedia>,
+}
+
+#[derive(Deserialize)]
+struct ImgurMedia {
+    url: String,
+}
+
+/// Image urls of an imgur album api response
+pub fn album_images(json: &str) -> Result<Vec<String>, serde_json::Error> {
+    let album: ImgurAlbum = serde_json::from_str(json)?;
+    Ok(album.media.into_iter().map(|media| media.url).collect())
+}
+
+/// The `og:image` meta tag of a html page without its query
+pub fn og_image(html: &str) -> Option<String> {
+    html.split("<meta").skip(1).find_map(|tag| {
+        let tag = tag.split('>').next()?;
+        if !tag.contains(r#"property="og:image""#) && !tag.contains(r#"name="og:image""#) {
+            return None;
+        }
+        let content = tag.split(r#"content=""#).nth(1)?.split('"').next()?;
+        let mut url = Url::parse(&content.replace("&amp;", "&")).ok()?;
+        url.set_query(None);
+        Some(url.to_string())
+    })
+}
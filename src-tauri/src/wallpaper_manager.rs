@@ -1,4 +1,5 @@
-use image::io::Reader;
+use futures_util::{stream, StreamExt};
+use image::{io::Reader, ImageError};
 use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use tauri::{
@@ -8,19 +9,50 @@ use tauri::{
     },
     async_runtime::spawn_blocking,
 };
-use tokio::fs::create_dir;
+use tokio::{
+    sync::{broadcast, watch, Notify},
+    time::sleep,
+};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    client::{ClientError, RedditClient},
-    Config, Post, WallpaperError, VALID_EXTENSION,
+    cache_schema::{self, CacheError, CACHE_VERSION},
+    client::{
+        ClientError, Downloads, FailedDownload, ListingPage, ListingSource, RateBudget,
+        RedditClient, RequestScheduler,
+    },
+    credential_store::{CredentialError, CredentialStore},
+    is_image_url,
+    library_store::{LibraryChanges, LibraryStore, SqliteStore, StoreError},
+    oauth,
+    progress::{FetchEvent, Progress},
+    resolver::Resolvers,
+    url_extension, Config, Post, WallpaperError, VALID_EXTENSION,
 };
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::{self, create_dir_all},
-    path::PathBuf,
-    sync::{Arc, Mutex},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::Duration,
 };
 
+/// How many older caches are kept next to the cache
+const CACHE_BACKUPS: usize = 3;
+
+/// How long `autosave` waits for more changes before it saves
+pub const AUTOSAVE_DELAY: Duration = Duration::from_secs(5);
+
+/// Failed downloads are retried by this many fetches before they are given up
+const MAX_DOWNLOAD_ATTEMPTS: u32 = 3;
+
+/// Resolves to the result of the running fetch once it finished
+type FetchResult = watch::Receiver<Option<Result<(), ClientError>>>;
+
 #[derive(Default, Serialize, Deserialize, Clone)]
 pub struct PostInfo {
     selected: bool,
@@ -31,28 +63,69 @@ pub struct Wallpaper {
     pub subreddit: String,
     pub title: String,
     pub url: String,
+    /// Unique name of the image, see `Image::name`
     pub name: String,
     pub file_name: String,
+    /// Fullname of the post the image belongs to
+    pub post_name: String,
+    /// Name of the profile that saved the post
+    pub profile: String,
+    /// Image format detected from the content, also the extension of `file_name`
+    pub format: String,
 }
 
 pub struct WallpaperManager {
     pub config: Mutex<Config>,
     post_data: Mutex<HashMap<String, PostInfo>>,
-    reddit_client: Mutex<Option<RedditClient>>,
+    reddit_client: Mutex<Option<Arc<RedditClient>>>,
+    /// Fetch of recent wallpapers that is currently running
+    in_flight: Mutex<Option<FetchResult>>,
+    /// Parent of the tokens of the running fetch and retries
+    cancel: Mutex<CancellationToken>,
+    progress: Progress,
+    scheduler: Arc<RequestScheduler>,
+    /// The whole library without a `library`, looked up in the database otherwise
     wallpapers: Mutex<Vec<Arc<Wallpaper>>>,
-    last_seen_wallpaper: Mutex<String>,
+    /// Newest post seen per profile
+    last_seen_wallpaper: Mutex<HashMap<String, String>>,
+    /// Images whose download failed, retried by the next fetch
+    failed_downloads: Mutex<Vec<FailedDownload>>,
+    /// Where the library is saved, wins over `cache_file`
+    library: Option<Box<dyn LibraryStore>>,
+    /// Wallpapers that were added after the library was saved
+    unsaved: Mutex<Vec<Arc<Wallpaper>>>,
+    /// Where the library is saved without a `library`, not saved at all if `None`
+    cache_file: Option<PathBuf>,
+    /// Held while the cache is written
+    saving: Mutex<()>,
+    /// Whether the library changed since it was saved
+    dirty: AtomicBool,
+    /// Wakes `autosave`
+    changed: Notify,
+    /// Why the library couldn't be loaded from the cache
+    cache_error: Mutex<Option<CacheError>>,
+    /// Where the config is saved, not saved at all if `None`
+    config_file: Option<PathBuf>,
+    /// Passphrase of the credential store, `None` if it uses the key file
+    passphrase: Mutex<Option<String>>,
 }
 
+/// The library as it is saved, older layouts are migrated by `cache_schema`
 #[derive(Serialize, Deserialize)]
 pub struct CachData {
+    version: u32,
     post_data: HashMap<String, PostInfo>,
     posts: Vec<Wallpaper>,
-    last_seen_wallpaper: String,
+    /// Newest post seen per profile
+    last_seen_wallpapers: HashMap<String, String>,
+    /// Images that are downloaded again by the next fetch
+    failed_downloads: Vec<FailedDownload>,
 }
 
 impl From<&WallpaperManager> for CachData {
     fn from(wm: &WallpaperManager) -> Self {
         Self {
+            version: CACHE_VERSION,
             post_data: (*wm.post_data.lock().unwrap()).clone(),
             posts: wm
                 .wallpapers
@@ -61,7 +134,8 @@ impl From<&WallpaperManager> for CachData {
                 .iter()
                 .map(|post| (**post).clone())
                 .collect::<Vec<_>>(),
-            last_seen_wallpaper: wm.last_seen_wallpaper.lock().unwrap().clone(),
+            last_seen_wallpapers: wm.last_seen_wallpaper.lock().unwrap().clone(),
+            failed_downloads: wm.failed_downloads.lock().unwrap().clone(),
         }
     }
 }
@@ -72,22 +146,167 @@ impl WallpaperManager {
     /// Tries to load config from filesystem
     pub async fn new() -> Self {
         // load config
-        let config = Self::load_config().unwrap_or_default();
+        let config_path = Self::config_path();
+        let config = match &config_path {
+            Some(path) => Self::read_config(path),
+            None => {
+                warn!("can't create config path");
+                Config::default()
+            }
+        };
+
+        // load post_data and wallpapers
+        let mut wm = Self::with_config(config).await;
+        if let Some(path) = config_path {
+            wm = wm.with_config_file(path);
+        }
+        match Self::cache_path() {
+            Some(path) => wm.with_library_file(&path.with_file_name("library.sqlite"), &path),
+            None => {
+                warn!("can't create cache path");
+                wm
+            }
+        }
+    }
+
+    /// Saves the config to `path` from now on, and its secrets to the credential store next to it
+    pub fn with_config_file(self, path: PathBuf) -> Self {
+        Self {
+            config_file: Some(path),
+            ..self
+        }
+    }
+
+    /// Loads the library from `path` and saves it there from now on
+    pub fn with_cache_file(self, path: PathBuf) -> Self {
+        match Self::load_cache(&path) {
+            Ok(Some(cache)) => {
+                *self.post_data.lock().unwrap() = cache.post_data;
+                *self.wallpapers.lock().unwrap() = cache.posts.into_iter().map(Arc::new).collect();
+                *self.last_seen_wallpaper.lock().unwrap() = cache.last_seen_wallpapers;
+                *self.failed_downloads.lock().unwrap() = cache.failed_downloads;
+            }
+            Ok(None) => {}
+            // the next save rotates the cache away
+            Err(e) => self.keep_unreadable_cache(&path, e),
+        }
+        Self {
+            cache_file: Some(path),
+            ..self
+        }
+    }
+
+    /// Opens the library database at `database`, see `with_library`
+    /// If it can't be opened the library is neither loaded nor saved,
+    /// the cache may have been imported into it already
+    pub fn with_library_file(self, database: &Path, cache: &Path) -> Self {
+        match SqliteStore::open(database) {
+            Ok(library) => self.with_library(library, cache),
+            Err(e) => {
+                warn!("unable to open the library: {e}");
+                *self.cache_error.lock().unwrap() = Some(e.into());
+                self
+            }
+        }
+    }
 
+    /// Loads the library from `library` and saves it there from now on
+    /// A new library imports the cache at `cache` once
+    pub fn with_library(self, library: impl LibraryStore + 'static, cache: &Path) -> Self {
+        match library.is_empty() {
+            Ok(true) => self.import_cache(&library, cache),
+            Ok(false) => {}
+            Err(e) => warn!("unable to read the library: {e}"),
+        }
+        match library.load() {
+            Ok(loaded) => {
+                *self.post_data.lock().unwrap() = loaded.post_data;
+                *self.last_seen_wallpaper.lock().unwrap() = loaded.cursors;
+                *self.failed_downloads.lock().unwrap() = loaded.failed_downloads;
+            }
+            Err(e) => {
+                warn!("unable to load the library: {e}");
+                *self.cache_error.lock().unwrap() = Some(e.into());
+            }
+        }
+        Self {
+            library: Some(Box::new(library)),
+            ..self
+        }
+    }
+
+    /// Copies the cache at `cache` into `library`
+    /// The cache is renamed afterwards, so it isn't imported again
+    fn import_cache(&self, library: &dyn LibraryStore, cache: &Path) {
+        let data = match Self::load_cache(cache) {
+            Ok(Some(data)) => data,
+            Ok(None) => return,
+            Err(e) => return self.keep_unreadable_cache(cache, e),
+        };
+        let wallpapers = data.posts.into_iter().map(Arc::new).collect::<Vec<_>>();
+        let imported = library.save(&LibraryChanges {
+            wallpapers: &wallpapers,
+            post_data: &data.post_data,
+            cursors: &data.last_seen_wallpapers,
+            failed_downloads: &data.failed_downloads,
+        });
+        match imported {
+            Ok(()) => {
+                info!("imported {} wallpapers from the cache", wallpapers.len());
+                fs::rename(cache, cache.with_extension("json.imported"))
+                    .map_err(|e| warn!("unable to rename the imported cache: {e}"))
+                    .ok();
+            }
+            Err(e) => warn!("unable to import the cache: {e}"),
+        }
+    }
+
+    /// Keeps a copy of the cache at `path` that couldn't be loaded, for the user to recover,
+    /// and reports `error` to the ui
+    fn keep_unreadable_cache(&self, path: &Path, error: CacheError) {
+        let kept = path.with_extension("json.broken");
+        if let Err(e) = fs::copy(path, &kept) {
+            warn!("unable to keep the unreadable cache: {e}");
+        }
+        warn!("unable to load the library: {error}");
+        *self.cache_error.lock().unwrap() = Some(error);
+    }
+
+    /// Create a WallpaperManager with an empty library
+    /// Neither reads nor writes config or cache on disk
+    pub async fn with_config(config: Config) -> Self {
         // create client using config
-        let reddit_client = RedditClient::new(&config).await;
+        let scheduler = Arc::new(RequestScheduler::new());
+        let progress = Progress::default();
+        let reddit_client = RedditClient::new(&config, scheduler.clone()).await;
         if let Err(e) = &reddit_client {
             warn!("{e}")
         }
 
-        // load post_data and wallpapers
-        let (post_data, wallpapers, last_seen_wallpaper) = Self::load_cache().unwrap_or_default();
         Self {
-            reddit_client: Mutex::new(reddit_client.ok()),
+            reddit_client: Mutex::new(
+                reddit_client
+                    .ok()
+                    .map(|client| Arc::new(client.with_progress(progress.clone()))),
+            ),
+            in_flight: Default::default(),
+            cancel: Default::default(),
+            progress,
+            library: None,
+            unsaved: Default::default(),
+            cache_file: None,
+            saving: Default::default(),
+            dirty: Default::default(),
+            changed: Default::default(),
+            scheduler,
             config: Mutex::new(config),
-            post_data,
-            wallpapers,
-            last_seen_wallpaper,
+            post_data: Default::default(),
+            wallpapers: Default::default(),
+            last_seen_wallpaper: Default::default(),
+            failed_downloads: Default::default(),
+            cache_error: Default::default(),
+            config_file: None,
+            passphrase: Default::default(),
         }
     }
 
@@ -98,31 +317,71 @@ impl WallpaperManager {
         })
     }
 
+    /// Reads the config at `path` and its secrets from the credential store next to it
+    pub fn read_config(path: &Path) -> Config {
+        let mut config = Self::load_config(path).unwrap_or_default();
+        Self::load_secrets(path, &mut config);
+        config
+    }
+
     /// Tries to read config from filesystem
-    fn load_config() -> Option<Config> {
-        if let Some(path) = Self::config_path() {
-            let data = read_string(path)
-                .ok()
-                .map(|content| toml::from_str::<Config>(&content).unwrap());
-            info!("successfully loaded config");
-            data
-        } else {
-            warn!("can't create config path");
-            None
-        }
+    fn load_config(path: &Path) -> Option<Config> {
+        let data = read_string(path)
+            .ok()
+            .map(|content| toml::from_str::<Config>(&content).unwrap());
+        info!("successfully loaded config");
+        data
     }
 
-    fn save_config(config: &Config) -> anyhow::Result<()> {
-        if let Some(path) = Self::config_path() {
-            info!("saving config at {path:?}");
-            let parent = path.parent().unwrap();
-            if !parent.is_dir() {
-                create_dir_all(parent)?;
+    /// The credential store next to the config file
+    fn credential_store(&self) -> Option<CredentialStore> {
+        self.config_file
+            .as_deref()
+            .and_then(Path::parent)
+            .map(CredentialStore::new)
+    }
+
+    /// Fills the secrets of `config` from the credential store next to the config file at `path`
+    /// Moves secrets of older config files into the store
+    fn load_secrets(path: &Path, config: &mut Config) {
+        let store = match path.parent() {
+            Some(parent) => CredentialStore::new(parent),
+            None => return,
+        };
+        match store.migrate_plaintext(config, None) {
+            // the store is never written here, it could be protected by a passphrase
+            Ok(true) => {
+                Self::write_config_file(path, config)
+                    .map_err(|e| warn!("{e}"))
+                    .ok();
             }
-            fs::write(path, toml::to_string(&config).unwrap()).unwrap();
-        } else {
-            warn!("can't create config path");
+            Ok(false) => {}
+            Err(e) => return warn!("unable to migrate credentials: {e}"),
+        }
+        match store.load(None) {
+            Ok(secrets) => config.set_secrets(secrets),
+            Err(CredentialError::Locked) => {
+                info!("credentials are locked by a passphrase");
+                config.set_secrets(Default::default());
+            }
+            Err(e) => warn!("unable to load credentials: {e}"),
         }
+    }
+
+    /// Writes the secrets of `config` to the credential store and the rest to the config file
+    fn save_config(path: &Path, config: &Config, passphrase: Option<&str>) -> anyhow::Result<()> {
+        info!("saving config at {path:?}");
+        let parent = path.parent().unwrap();
+        if !parent.is_dir() {
+            create_dir_all(parent)?;
+        }
+        CredentialStore::new(parent).save(&config.secrets(), passphrase)?;
+        Self::write_config_file(path, config)
+    }
+
+    /// Writes `config` to the config file at `path`, without its secrets
+    fn write_config_file(path: &Path, config: &Config) -> anyhow::Result<()> {
+        fs::write(path, toml::to_string(&config.without_secrets())?)?;
         Ok(())
     }
 
@@ -133,184 +392,721 @@ impl WallpaperManager {
         })
     }
 
-    fn load_cache() -> Option<(
-        Mutex<HashMap<String, PostInfo>>,
-        Mutex<Vec<Arc<Wallpaper>>>,
-        Mutex<String>,
-    )> {
-        if let Some(path) = Self::cache_path() {
-            let data = read_string(path)
-                .ok()
-                .and_then(|content| serde_json::from_str::<CachData>(&content).ok())
-                .map(|a| {
-                    (
-                        Mutex::new(a.post_data),
-                        Mutex::new(a.posts.into_iter().map(Arc::new).collect::<Vec<_>>()),
-                        Mutex::new(a.last_seen_wallpaper),
-                    )
-                });
-            info!("successfully loaded cache");
-            data
-        } else {
-            warn!("can't create cache path");
-            None
+    /// Reads the library from `path` or, if that can't be read, its newest readable backup
+    /// `None` if there is no cache yet
+    fn load_cache(path: &Path) -> Result<Option<CachData>, CacheError> {
+        let mut error = None;
+        let candidates = std::iter::once(path.to_owned())
+            .chain((1..=CACHE_BACKUPS).map(|i| backup_path(path, i)))
+            .filter(|path| path.exists());
+        for candidate in candidates {
+            match Self::read_cache(&candidate) {
+                Ok(cache) => {
+                    info!("successfully loaded cache from {candidate:?}");
+                    return Ok(Some(cache));
+                }
+                // backups are older still, the app has to be updated to read it
+                Err(e @ CacheError::Newer(_)) => return Err(e),
+                Err(e) => {
+                    warn!("unable to read the cache at {candidate:?}: {e}");
+                    error.get_or_insert(e);
+                }
+            }
         }
+        error.map_or(Ok(None), Err)
+    }
+
+    /// Reads a cache of any version, migrating older layouts to the current one
+    fn read_cache(path: &Path) -> Result<CachData, CacheError> {
+        let unreadable = |e: &dyn std::fmt::Display| CacheError::Unreadable(e.to_string());
+        let content = read_string(path).map_err(|e| unreadable(&e))?;
+        let mut cache = serde_json::from_str(&content).map_err(|e| unreadable(&e))?;
+        cache_schema::migrate(&mut cache)?;
+        serde_json::from_value(cache).map_err(|e| unreadable(&e))
+    }
+
+    /// Why the library couldn't be loaded, if it couldn't
+    pub fn cache_error(&self) -> Option<CacheError> {
+        self.cache_error.lock().unwrap().clone()
     }
 
     /// Save cache to disk
+    /// Does nothing without a library or cache file
+    /// The cache is written to a temporary file first, so a crash never leaves
+    /// a half-written cache behind, and the previous caches are kept as backups
     pub fn save_cache(&self) -> anyhow::Result<()> {
-        if let Some(path) = Self::cache_path() {
+        if let Some(library) = &self.library {
+            self.save_library(library.as_ref())?;
+        } else if let Some(path) = &self.cache_file {
+            let _saving = self.saving.lock().unwrap();
+            self.dirty.store(false, Ordering::SeqCst);
             info!("saving cache at {path:?}");
             let parent = path.parent().unwrap();
             if !parent.exists() {
                 create_dir_all(parent)?;
             }
             let data = serde_json::to_string(&CachData::from(self)).unwrap();
-            fs::write(path, data)?;
-        } else {
-            warn!("can't create config path");
+            let temp_path = path.with_extension("json.tmp");
+            let mut file = fs::File::create(&temp_path)?;
+            file.write_all(data.as_bytes())?;
+            file.sync_all()?;
+
+            for i in (1..CACHE_BACKUPS).rev() {
+                let backup = backup_path(path, i);
+                if backup.exists() {
+                    fs::rename(&backup, backup_path(path, i + 1))?;
+                }
+            }
+            if path.exists() {
+                fs::rename(path, backup_path(path, 1))?;
+            }
+            fs::rename(&temp_path, path)?;
         }
         Ok(())
     }
 
+    /// Saves the wallpapers that were added since the last save, and everything else
+    /// that is small enough to be saved in full
+    fn save_library(&self, library: &dyn LibraryStore) -> Result<(), StoreError> {
+        let _saving = self.saving.lock().unwrap();
+        self.dirty.store(false, Ordering::SeqCst);
+        let unsaved = std::mem::take(&mut *self.unsaved.lock().unwrap());
+        let post_data = {
+            let post_data = self.post_data.lock().unwrap();
+            unsaved
+                .iter()
+                .filter_map(|wallpaper| {
+                    let info = post_data.get(&wallpaper.name)?;
+                    Some((wallpaper.name.clone(), info.clone()))
+                })
+                .collect()
+        };
+        let cursors = self.last_seen_wallpaper.lock().unwrap().clone();
+        let failed_downloads = self.failed_downloads.lock().unwrap().clone();
+        let saved = library.save(&LibraryChanges {
+            wallpapers: &unsaved,
+            post_data: &post_data,
+            cursors: &cursors,
+            failed_downloads: &failed_downloads,
+        });
+        if saved.is_err() {
+            // saved by the next attempt, wallpapers added in between after them
+            let mut pending = self.unsaved.lock().unwrap();
+            pending.splice(0..0, unsaved);
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+        saved
+    }
+
+    /// Saves the cache a moment after the library changed
+    /// Changes that follow each other closely are saved together
+    pub async fn autosave(&self, delay: Duration) {
+        loop {
+            self.changed.notified().await;
+            sleep(delay).await;
+            if self.dirty.load(Ordering::SeqCst) {
+                self.save_cache().map_err(|e| warn!("{e}")).ok();
+            }
+        }
+    }
+
+    /// Marks the library as changed for `autosave`
+    fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::SeqCst);
+        self.changed.notify_one();
+    }
+
     /// Fetch all wallpapers
     pub async fn fetch_all_wallpapers(&self) -> Result<Vec<Post>, ClientError> {
-        let client = self.get_client()?;
-        let wallpapers = client
-            .fetch_all_saved_posts()
-            .await
+        let posts = self.get_client()?.fetch_all_saved_posts().await;
+        let config = self.config.lock().unwrap();
+        Ok(posts?
             .into_iter()
-            .filter(|post| post.subreddit == "wallpaper")
-            .collect::<Vec<_>>();
-        self.put_client(client);
-        Ok(wallpapers)
+            .filter(|post| {
+                config.accepts_subreddit(&post.subreddit)
+                    && (!config.any_subreddit || post.images.iter().any(|i| is_image_url(&i.url)))
+            })
+            .collect::<Vec<_>>())
     }
 
-    /// Get the cached wallpaper
-    /// FIXME: don't clone
-    pub async fn get_cached_wallpapers(&self) -> Vec<Arc<Wallpaper>> {
-        self.wallpapers.lock().unwrap().clone()
+    /// All wallpapers in the order they were added
+    pub async fn get_cached_wallpapers(&self) -> Result<Vec<Wallpaper>, StoreError> {
+        self.find_wallpapers(|library| library.wallpapers(), |_| true)
     }
 
     /// Set a wallpaper as system-wallpaper
-    pub async fn set_wallpaper(&self, name: &str) {
+    pub async fn set_wallpaper(&self, name: &str) -> Result<(), WallpaperError> {
         let wallpaper = self
-            .get_wallpaper(name)
-            .unwrap_or_else(|| panic!("no wallpaper with name {} exists", name));
-        let config = self.config.lock().unwrap();
-        let path = config.path.join(&wallpaper.file_name);
+            .get_wallpaper(name)?
+            .ok_or_else(|| WallpaperError::UnknownWallpaper(name.to_owned()))?;
+        let path = self.wallpaper_path().join(&wallpaper.file_name);
         let path = path.to_str().unwrap();
         info!("setting wallpaper: {:?}", path);
-        wallpaper::set_from_path(path).unwrap();
+        wallpaper::set_from_path(path).map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Looks the wallpaper up in the library,
+    /// only those that were added since it was saved are searched in memory
+    pub fn get_wallpaper(&self, name: &str) -> Result<Option<Arc<Wallpaper>>, StoreError> {
+        // a running save holds wallpapers that are neither unsaved nor in the library yet
+        let _saving = self.saving.lock().unwrap();
+        if let Some(wallpaper) = self.library.as_ref().map(|library| library.wallpaper(name)) {
+            if let Some(wallpaper) = wallpaper? {
+                return Ok(Some(Arc::new(wallpaper)));
+            }
+        }
+        let in_memory = self.in_memory().lock().unwrap();
+        Ok(in_memory.iter().find(|wp| wp.name == name).cloned())
+    }
+
+    /// Wallpapers from `subreddit`, compared case-insensitively
+    pub fn wallpapers_of_subreddit(&self, subreddit: &str) -> Result<Vec<Wallpaper>, StoreError> {
+        self.find_wallpapers(
+            |library| library.wallpapers_of_subreddit(subreddit),
+            |wallpaper| wallpaper.subreddit.eq_ignore_ascii_case(subreddit),
+        )
+    }
+
+    /// The wallpapers `query` finds in the library followed by those in memory that `matches`
+    fn find_wallpapers(
+        &self,
+        query: impl FnOnce(&dyn LibraryStore) -> Result<Vec<Wallpaper>, StoreError>,
+        matches: impl Fn(&Wallpaper) -> bool,
+    ) -> Result<Vec<Wallpaper>, StoreError> {
+        let _saving = self.saving.lock().unwrap();
+        let mut wallpapers = match &self.library {
+            Some(library) => query(library.as_ref())?,
+            None => vec![],
+        };
+        let in_memory = self.in_memory().lock().unwrap();
+        wallpapers.extend(
+            in_memory
+                .iter()
+                .filter(|wallpaper| matches(wallpaper))
+                .map(|wallpaper| (**wallpaper).clone()),
+        );
+        Ok(wallpapers)
+    }
+
+    /// Drops the posts that are in the library already and those listed twice,
+    /// a post can show up twice when the listing shifts between two pages
+    fn new_posts(&self, posts: Vec<Post>) -> Result<Vec<Post>, StoreError> {
+        let _saving = self.saving.lock().unwrap();
+        let in_memory = self.in_memory().lock().unwrap();
+        let mut seen = HashSet::new();
+        let mut new_posts = vec![];
+        for post in posts {
+            let in_library = match &self.library {
+                Some(library) => library.has_post(&post.name)?,
+                None => false,
+            };
+            let present = in_library || in_memory.iter().any(|wp| wp.post_name == post.name);
+            if !present && seen.insert(post.name.clone()) {
+                new_posts.push(post);
+            }
+        }
+        Ok(new_posts)
+    }
+
+    /// Wallpapers that are looked up in memory,
+    /// with a library only those that were added since it was saved
+    fn in_memory(&self) -> &Mutex<Vec<Arc<Wallpaper>>> {
+        match self.library {
+            Some(_) => &self.unsaved,
+            None => &self.wallpapers,
+        }
     }
 
-    fn get_wallpaper(&self, name: &str) -> Option<Arc<Wallpaper>> {
-        self.wallpapers
+    /// Adds `wallpapers` to the library, they are saved with the next save
+    fn add_wallpapers(&self, wallpapers: Vec<Arc<Wallpaper>>) {
+        self.in_memory().lock().unwrap().extend(wallpapers);
+        self.mark_dirty();
+    }
+
+    /// Wallpapers with the tag `tag`
+    pub fn wallpapers_tagged(&self, tag: &str) -> Result<Vec<Wallpaper>, WallpaperError> {
+        Ok(self.library()?.wallpapers_tagged(tag)?)
+    }
+
+    pub fn tags(&self, name: &str) -> Result<Vec<String>, WallpaperError> {
+        Ok(self.library()?.tags(name)?)
+    }
+
+    /// Replaces the tags of the wallpaper `name`
+    pub fn set_tags(&self, name: &str, tags: &[String]) -> Result<(), WallpaperError> {
+        let library = self.library()?;
+        // tags can only be given to wallpapers in the library
+        let unsaved = self
+            .unsaved
             .lock()
             .unwrap()
             .iter()
-            .find(|post| post.name == name)
-            .cloned()
+            .any(|wp| wp.name == name);
+        if unsaved {
+            self.save_library(library)?;
+        }
+        Ok(library.set_tags(name, tags)?)
     }
 
-    fn get_client(&self) -> Result<RedditClient, ClientError> {
-        let rc = self.reddit_client.lock().unwrap().take();
-        rc.ok_or(ClientError::BadCredetials)
+    /// Tags are only kept in the library database
+    fn library(&self) -> Result<&dyn LibraryStore, WallpaperError> {
+        self.library.as_deref().ok_or(WallpaperError::NoLibrary)
     }
 
-    fn put_client(&self, client: RedditClient) {
-        *self.reddit_client.lock().unwrap() = Some(client)
+    /// The client stays in place while requests use it, so commands can run concurrently
+    fn get_client(&self) -> Result<Arc<RedditClient>, ClientError> {
+        self.reddit_client
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or(ClientError::BadCredetials)
+    }
+
+    async fn login(&self, config: &Config) -> Result<RedditClient, ClientError> {
+        let client = RedditClient::new(config, self.scheduler.clone()).await?;
+        Ok(client.with_progress(self.progress.clone()))
+    }
+
+    /// Receives the `FetchEvent`s of all following fetches
+    pub fn subscribe(&self) -> broadcast::Receiver<FetchEvent> {
+        self.progress.subscribe()
+    }
+
+    fn set_client(&self, client: Option<RedditClient>) {
+        *self.reddit_client.lock().unwrap() = client.map(Arc::new);
     }
 
     /// Fetch all new wallpapers from reddit app
+    /// Calls that arrive while a fetch is running wait for it and share its result
     pub async fn fetch_recent_wallpapers(&self) -> Result<(), ClientError> {
-        let client = self.get_client()?;
+        loop {
+            let (mut result, sender) = {
+                let mut in_flight = self.in_flight.lock().unwrap();
+                match &*in_flight {
+                    Some(result) => (result.clone(), None),
+                    None => {
+                        let (sender, result) = watch::channel(None);
+                        *in_flight = Some(result.clone());
+                        (result, Some(sender))
+                    }
+                }
+            };
+
+            if let Some(sender) = sender {
+                let guard = InFlightGuard(&self.in_flight);
+                let result = match self.get_client() {
+                    Ok(client) => self.fetch_recent_with(&client).await,
+                    Err(e) => Err(e),
+                };
+                // later calls start a new fetch instead of receiving this result
+                drop(guard);
+                sender.send_replace(Some(result.clone()));
+                return result;
+            }
+
+            // the leading fetch was dropped without a result, start a new one
+            if let Ok(result) = result.wait_for(Option::is_some).await {
+                return result.clone().expect("waited for a result");
+            }
+        }
+    }
+
+    async fn fetch_recent_with(&self, client: &RedditClient) -> Result<(), ClientError> {
         info!("started fetching wallpapers");
+        // the client belongs to the profile that was active when the fetch started
+        let profile = self.config.lock().unwrap().active_profile.clone();
+        let cancel = self.cancel_token();
+
         // request all new post
-        let posts = {
-            let data = &self.last_seen_wallpaper.lock().unwrap().clone();
-            let (posts, new_last_senn) = client.fetch_saved_until(data).await;
-            *self.last_seen_wallpaper.lock().unwrap() = new_last_senn;
-            posts
+        let (posts, new_last_senn) = {
+            let data = self
+                .last_seen_wallpaper
+                .lock()
+                .unwrap()
+                .get(&profile)
+                .cloned()
+                .unwrap_or_default();
+            client.fetch_saved_until(&data, &cancel).await?
         };
 
         // filter posts
+        // the downloads decide by their content which posts are images,
+        // but most posts aren't images when all subreddits are accepted
+        // so only links to image files are tried then
+        let any_subreddit = self.config.lock().unwrap().any_subreddit;
+        let mut skipped = vec![];
+        let posts = self.new_posts(posts)?;
         let posts = {
-            let wallpapers = self.wallpapers.lock().unwrap();
+            let config = self.config.lock().unwrap();
             posts
                 .into_iter()
                 .filter(|post| {
-                    let wallpapers_subreddit = post.subreddit == "wallpaper";
-                    let already_present = wallpapers.iter().any(|wp| *wp.name == post.name);
-                    let valid_extension =
-                        VALID_EXTENSION.contains(&post.url.split('.').last().unwrap());
-
-                    if wallpapers_subreddit && !valid_extension {
-                        warn!(
-                            "not adding resource {}, because it has no valid picture-ending",
-                            post.url
-                        );
+                    let accepted_subreddit = config.accepts_subreddit(&post.subreddit);
+                    if !accepted_subreddit {
+                        let reason = format!("r/{} is not an accepted subreddit", post.subreddit);
+                        skipped.extend(rejections(post, &reason));
                     }
-
-                    valid_extension && wallpapers_subreddit && !already_present
+                    accepted_subreddit
                 })
-                .map(Arc::from)
                 .collect::<Vec<_>>()
         };
 
+        let (posts, no_image) = self.image_posts(posts, any_subreddit).await;
+        for post in &no_image {
+            skipped.extend(rejections(post, "the post links to no image file"));
+        }
+        self.record_failures(skipped);
+
+        // retry the images that failed before, unless their post was listed again
+        let retries = {
+            let mut failed_downloads = self.failed_downloads.lock().unwrap();
+            let (retries, kept) = failed_downloads
+                .drain(..)
+                .filter(|failed| {
+                    !posts.iter().any(|post| {
+                        post.images
+                            .iter()
+                            .any(|image| image.name == failed.image.name)
+                    })
+                })
+                .partition(|failed| !failed.rejected && failed.attempts < MAX_DOWNLOAD_ATTEMPTS);
+            *failed_downloads = kept;
+            retries
+        };
+
+        self.download_posts(client, posts, retries, &profile, &cancel)
+            .await;
+
+        // the posts that were not stored must be listed again by the next fetch
+        if cancel.is_cancelled() {
+            info!("fetch was cancelled");
+            self.flush_cache()?;
+            return Err(ClientError::Cancelled);
+        }
+
+        // the cursor only moves once the library it belongs to is on disk
+        let previous = self
+            .last_seen_wallpaper
+            .lock()
+            .unwrap()
+            .insert(profile.clone(), new_last_senn);
+        if let Err(e) = self.flush_cache() {
+            let mut last_seen = self.last_seen_wallpaper.lock().unwrap();
+            match previous {
+                Some(previous) => last_seen.insert(profile, previous),
+                None => last_seen.remove(&profile),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Saves the cache after a sync
+    fn flush_cache(&self) -> Result<(), ClientError> {
+        self.save_cache().map_err(|e| {
+            warn!("unable to save the cache: {e}");
+            ClientError::Persistence(e.to_string())
+        })
+    }
+
+    /// Downloads the images of `posts` and of the failed downloads in `retries`,
+    /// adds them to the library and records the failures
+    async fn download_posts(
+        &self,
+        client: &RedditClient,
+        posts: Vec<Post>,
+        retries: Vec<FailedDownload>,
+        profile: &str,
+        cancel: &CancellationToken,
+    ) {
+        let posts = posts
+            .into_iter()
+            .chain(retries.iter().map(|failed| Post {
+                images: vec![failed.image.clone()],
+                ..failed.post.clone()
+            }))
+            .map(Arc::new)
+            .collect::<Vec<_>>();
+        for post in &posts {
+            self.progress.emit(FetchEvent::PostQueued {
+                name: post.name.clone(),
+                title: post.title.clone(),
+                images: post.images.len(),
+            });
+        }
+
         // download all background images
-        let paths = client.downloader_post_images(&posts).await;
-        self.create_thumbnails(&paths).await;
+        let Downloads { mut paths, failed } = client.downloader_post_images(&posts, cancel).await;
+        self.create_thumbnails(&paths, cancel).await;
+        if cancel.is_cancelled() {
+            // images without thumbnail stay on disk and are picked up by the next fetch
+            let thumbnails_path = self.wallpaper_path().join("thumbnails");
+            paths.retain(|_, file_name| thumbnails_path.join(file_name).exists());
+        }
 
-        // create info for all the posts
-        posts.iter().for_each(|post| {
+        // count the attempts of retried images, and keep those a cancel didn't get to
+        let failed = failed
+            .into_iter()
+            .map(|mut failed| {
+                if let Some(retry) = retries.iter().find(|r| r.image.name == failed.image.name) {
+                    failed.attempts += retry.attempts;
+                }
+                failed
+            })
+            .collect::<Vec<_>>();
+        let unfinished = retries
+            .into_iter()
+            .filter(|retry| {
+                !paths.contains_key(&retry.image.name)
+                    && !failed.iter().any(|f| f.image.name == retry.image.name)
+            })
+            .collect::<Vec<_>>();
+        self.record_failures(failed.into_iter().chain(unfinished));
+
+        // one wallpaper for every image that was downloaded
+        let wallpapers = posts
+            .iter()
+            .flat_map(|post| {
+                let paths = &paths;
+                post.images.iter().filter_map(move |image| {
+                    let file_name = paths.get(&image.name)?;
+                    Some(Arc::new(Wallpaper {
+                        subreddit: post.subreddit.clone(),
+                        title: post.title.clone(),
+                        url: image.url.clone(),
+                        name: image.name.clone(),
+                        file_name: file_name.clone(),
+                        post_name: post.name.clone(),
+                        profile: profile.to_owned(),
+                        // the downloader names the file after the detected format
+                        format: url_extension(file_name).unwrap_or_default().to_owned(),
+                    }))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // create info for all the wallpapers
+        wallpapers.iter().for_each(|wallpaper| {
             self.post_data
                 .lock()
                 .unwrap()
-                .insert(post.name.clone(), Default::default());
+                .insert(wallpaper.name.clone(), Default::default());
         });
 
-        let wallpapers = posts.into_iter().map(|post| {
-            let post = Arc::try_unwrap(post).unwrap();
-            Arc::new(Wallpaper {
-                subreddit: post.subreddit,
-                title: post.title,
-                url: post.url,
-                file_name: paths.get(&post.name).unwrap().clone(),
-                name: post.name,
-            })
+        let images = posts.iter().map(|post| post.images.len()).sum::<usize>();
+        self.progress.emit(FetchEvent::Finished {
+            posts: posts.len(),
+            images: wallpapers.len(),
+            failed: images - wallpapers.len(),
         });
+        info!("finished requesting images, {} new", wallpapers.len());
+        self.add_wallpapers(wallpapers);
+    }
 
-        self.wallpapers.lock().unwrap().extend(wallpapers);
-        info!(
-            "finished requesting images, new image count: {}",
-            self.wallpapers.lock().unwrap().len()
-        );
-        self.put_client(client);
+    /// Adds `failures` to the failed downloads, replacing older entries of the same images
+    fn record_failures(&self, failures: impl IntoIterator<Item = FailedDownload>) {
+        {
+            let mut failed_downloads = self.failed_downloads.lock().unwrap();
+            for failure in failures {
+                failed_downloads.retain(|failed| failed.image.name != failure.image.name);
+                failed_downloads.push(failure);
+            }
+        }
+        self.mark_dirty();
+    }
+
+    /// Images that were skipped or could not be downloaded
+    pub fn failed_downloads(&self) -> Vec<FailedDownload> {
+        self.failed_downloads.lock().unwrap().clone()
+    }
+
+    /// Downloads the failed images named in `names` again, even those that were rejected
+    pub async fn retry_failed_downloads(&self, names: &[String]) -> Result<(), ClientError> {
+        let client = self.get_client()?;
+        let profile = self.config.lock().unwrap().active_profile.clone();
+        let cancel = self.cancel_token();
+
+        let retries = {
+            let mut failed_downloads = self.failed_downloads.lock().unwrap();
+            let (retries, kept) = failed_downloads
+                .drain(..)
+                .partition(|failed| names.contains(&failed.image.name));
+            *failed_downloads = kept;
+            retries
+        };
+        self.download_posts(&client, vec![], retries, &profile, &cancel)
+            .await;
+        self.flush_cache()?;
+        if cancel.is_cancelled() {
+            return Err(ClientError::Cancelled);
+        }
         Ok(())
     }
 
-    async fn create_thumbnails(&self, paths: &HashMap<String, String>) {
+    /// Adds the images in the wallpaper directory that are missing from the library,
+    /// e.g. after the cache was lost, and creates the thumbnails that are missing
+    /// Titles and subreddits are fetched from reddit with `refetch_info`, otherwise they stay empty
+    /// Returns how many wallpapers were added
+    pub async fn rescan_library(&self, refetch_info: bool) -> Result<usize, WallpaperError> {
+        let files = scan_wallpaper_dir(&self.wallpaper_path())?;
+        let mut missing = vec![];
+        for (name, file_name) in &files {
+            if self.get_wallpaper(name)?.is_none() {
+                missing.push((name.clone(), file_name.clone()));
+            }
+        }
+
+        let posts = if refetch_info && !missing.is_empty() {
+            let mut names = missing
+                .iter()
+                .map(|(name, _)| post_name(name).to_owned())
+                .collect::<Vec<_>>();
+            names.sort();
+            names.dedup();
+            self.get_client()?.fetch_info(&names).await?
+        } else {
+            vec![]
+        };
+
+        let profile = self.config.lock().unwrap().active_profile.clone();
+        let wallpapers = missing
+            .into_iter()
+            .map(|(name, file_name)| {
+                let post_name = post_name(&name).to_owned();
+                let post = posts.iter().find(|post| post.name == post_name);
+                let url = post
+                    .and_then(|post| post.images.iter().find(|image| image.name == name))
+                    .map(|image| image.url.clone());
+                Arc::new(Wallpaper {
+                    subreddit: post.map(|post| post.subreddit.clone()).unwrap_or_default(),
+                    title: post.map(|post| post.title.clone()).unwrap_or_default(),
+                    url: url.unwrap_or_default(),
+                    // the downloader named the file after the detected format
+                    format: url_extension(&file_name).unwrap_or_default().to_owned(),
+                    name,
+                    file_name,
+                    post_name,
+                    profile: profile.clone(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // thumbnails that exist already are kept
+        let paths = files.into_iter().collect::<HashMap<_, _>>();
+        self.create_thumbnails(&paths, &CancellationToken::new())
+            .await;
+
+        let added = wallpapers.len();
+        info!("rescan found {added} wallpapers that were missing from the library");
+        {
+            let mut post_data = self.post_data.lock().unwrap();
+            for wallpaper in &wallpapers {
+                post_data.entry(wallpaper.name.clone()).or_default();
+            }
+        }
+        // an image on disk is no failed download anymore
+        self.failed_downloads
+            .lock()
+            .unwrap()
+            .retain(|failed| !wallpapers.iter().any(|wp| wp.name == failed.image.name));
+        self.add_wallpapers(wallpapers);
+        self.flush_cache()?;
+        Ok(added)
+    }
+
+    /// Stops the running fetch of recent wallpapers
+    /// Images that were downloaded completely are still added to the library
+    pub fn cancel_fetch(&self) {
+        // operations that start afterwards get a token that isn't cancelled
+        std::mem::take(&mut *self.cancel.lock().unwrap()).cancel();
+    }
+
+    /// Token of a new operation, cancelled by `cancel_fetch` together with all running ones
+    fn cancel_token(&self) -> CancellationToken {
+        self.cancel.lock().unwrap().child_token()
+    }
+
+    /// Resolves the image urls of `posts`
+    /// With `require_image_url` only images whose url has a valid extension are kept,
+    /// posts without any image left are returned separately
+    async fn image_posts(
+        &self,
+        mut posts: Vec<Post>,
+        require_image_url: bool,
+    ) -> (Vec<Post>, Vec<Post>) {
+        // turn links to image hosts into direct image urls
+        // as many at once as images are downloaded, so the hosts see the same limits
+        let (resolvers, concurrency) = {
+            let config = self.config.lock().unwrap();
+            (Resolvers::new(&config), config.downloads.concurrency.max(1))
+        };
+        stream::iter(posts.iter_mut())
+            .for_each_concurrent(concurrency, |post| resolvers.resolve(post, &self.scheduler))
+            .await;
+
+        let (mut posts, dropped): (Vec<_>, Vec<_>) = posts.into_iter().partition(|post| {
+            post.images
+                .iter()
+                .any(|image| !require_image_url || is_image_url(&image.url))
+        });
+        if require_image_url {
+            for post in &mut posts {
+                post.images.retain(|image| is_image_url(&image.url));
+            }
+        }
+        (posts, dropped)
+    }
+
+    /// Fetch one page of `source` with the image posts that are not in the library yet
+    pub async fn fetch_candidates(
+        &self,
+        source: &ListingSource,
+        after: Option<&str>,
+    ) -> Result<ListingPage, ClientError> {
+        let mut page = self.get_client()?.fetch_page(source, after).await?;
+        let posts = self.new_posts(page.posts)?;
+        page.posts = self.image_posts(posts, true).await.0;
+        Ok(page)
+    }
+
+    /// Thumbnails that weren't started when `cancel` is triggered are skipped
+    /// Images whose thumbnail can't be created are logged and skipped,
+    /// they get one when the library is scanned again
+    async fn create_thumbnails(&self, paths: &HashMap<String, String>, cancel: &CancellationToken) {
         let thumbnails_path = self.wallpaper_path().join("thumbnails");
-        if !thumbnails_path.exists() {
-            create_dir(&thumbnails_path).await.unwrap();
+        if let Err(e) = tokio::fs::create_dir_all(&thumbnails_path).await {
+            return warn!("unable to create the thumbnail directory: {e}");
         }
         let mut futures = vec![];
-        for file_name in paths.values() {
+        for (image, file_name) in paths {
+            let image = image.to_owned();
             let file_name = file_name.to_owned();
             let thumbnails_path = thumbnails_path.clone();
             let file_path = self.wallpaper_path().join(&file_name);
+            let progress = self.progress.clone();
+            let cancel = cancel.clone();
             let future = spawn_blocking(move || {
+                if cancel.is_cancelled() {
+                    return;
+                }
                 let single_path = thumbnails_path.join(&file_name);
                 if single_path.exists() {
+                    progress.emit(FetchEvent::Thumbnail { image });
                     return;
                 }
-                match Reader::open(&file_path).unwrap().decode() {
-                    Ok(image) => {
-                        let factor = image.height() as f32 / image.width() as f32;
-                        let thumbnail = image.thumbnail(300, (300. * factor) as u32);
-                        thumbnail.save(&single_path).unwrap();
+                let decoded = Reader::open(&file_path)
+                    .map_err(ImageError::from)
+                    .and_then(|reader| reader.decode());
+                let thumbnail = decoded.and_then(|decoded| {
+                    let factor = decoded.height() as f32 / decoded.width() as f32;
+                    let thumbnail = decoded.thumbnail(300, (300. * factor) as u32);
+                    thumbnail.save(&single_path)
+                });
+                match thumbnail {
+                    Ok(()) => {
                         info!("generated thumbnail {:?}", &single_path);
+                        progress.emit(FetchEvent::Thumbnail { image });
                     }
                     Err(e) => {
                         warn!("unable to create thumbnail for {file_name} because: {e}")
@@ -320,7 +1116,9 @@ impl WallpaperManager {
             futures.push(future);
         }
         for future in futures {
-            future.await.unwrap();
+            if let Err(e) = future.await {
+                warn!("thumbnail task failed: {e}");
+            }
         }
     }
 
@@ -329,18 +1127,194 @@ impl WallpaperManager {
     }
 
     pub async fn set_config(&self, config: Config) -> Result<(), WallpaperError> {
-        let client = RedditClient::new(&config).await?;
-        *self.reddit_client.lock().unwrap() = Some(client);
+        // the ui never sees all secrets of a locked store, saving would erase the others
+        if self.credentials_locked() {
+            return Err(CredentialError::Locked.into());
+        }
+        let client = self.login(&config).await?;
+        self.set_client(Some(client));
         create_dir_all(&config.path)?;
         if config.path.to_str().unwrap() == "" {
             return Err(WallpaperError::NoRootPaths);
         }
-        Self::save_config(&config).map_err(|e| warn!("{e}")).ok();
+        self.persist_config(&config);
         *self.config.lock().unwrap() = config;
         Ok(())
     }
 
+    /// Names of all profiles, the active one first
+    pub fn profiles(&self) -> Vec<String> {
+        self.config.lock().unwrap().profile_names()
+    }
+
+    /// Adds a profile without credentials and makes it the active one
+    /// Its credentials are set through `set_config` afterwards
+    pub fn add_profile(&self, name: &str) -> Result<(), WallpaperError> {
+        // saving the profiles of a locked store would erase their secrets
+        if self.credentials_locked() {
+            return Err(CredentialError::Locked.into());
+        }
+        let config = {
+            let mut config = self.config.lock().unwrap();
+            config.add_profile(name)?;
+            config.clone()
+        };
+        self.set_client(None);
+        self.persist_config(&config);
+        Ok(())
+    }
+
+    /// Makes the profile `name` the active one and logs in with its account
+    pub async fn switch_profile(&self, name: &str) -> Result<(), WallpaperError> {
+        if self.credentials_locked() {
+            return Err(CredentialError::Locked.into());
+        }
+        let config = {
+            let mut config = self.config.lock().unwrap();
+            config.switch_profile(name)?;
+            config.clone()
+        };
+        self.set_client(None);
+        self.persist_config(&config);
+        let client = self.login(&config).await?;
+        self.set_client(Some(client));
+        Ok(())
+    }
+
+    /// Does nothing without a config file
+    fn persist_config(&self, config: &Config) {
+        let path = match &self.config_file {
+            Some(path) => path,
+            None => return,
+        };
+        let passphrase = self.passphrase.lock().unwrap().clone();
+        Self::save_config(path, config, passphrase.as_deref())
+            .map_err(|e| warn!("{e}"))
+            .ok();
+    }
+
+    /// Whether the credential store needs a passphrase that wasn't given yet
+    pub fn credentials_locked(&self) -> bool {
+        self.passphrase.lock().unwrap().is_none()
+            && self
+                .credential_store()
+                .map_or(false, |store| store.needs_passphrase().unwrap_or(false))
+    }
+
+    /// Decrypts the credential store with `passphrase` and logs in
+    pub async fn unlock_credentials(&self, passphrase: String) -> Result<(), WallpaperError> {
+        let store = self.credential_store().ok_or(WallpaperError::NoRootPaths)?;
+        let secrets = store.load(Some(&passphrase))?;
+        let config = {
+            let mut config = self.config.lock().unwrap();
+            config.set_secrets(secrets);
+            config.clone()
+        };
+        *self.passphrase.lock().unwrap() = Some(passphrase);
+        let client = self.login(&config).await?;
+        self.set_client(Some(client));
+        Ok(())
+    }
+
+    /// Encrypts the credential store with `passphrase`
+    /// or with the machine-local key file if it is `None`
+    pub fn set_credentials_passphrase(
+        &self,
+        passphrase: Option<String>,
+    ) -> Result<(), WallpaperError> {
+        if self.credentials_locked() {
+            return Err(CredentialError::Locked.into());
+        }
+        let store = self.credential_store().ok_or(WallpaperError::NoRootPaths)?;
+        store.save(
+            &self.config.lock().unwrap().secrets(),
+            passphrase.as_deref(),
+        )?;
+        *self.passphrase.lock().unwrap() = passphrase;
+        Ok(())
+    }
+
+    /// Logs in through the browser and stores the refresh token instead of the password
+    /// `config` is used as base for the new config
+    pub async fn authorize(
+        &self,
+        mut config: Config,
+        open: impl FnOnce(&str) -> Result<(), String>,
+    ) -> Result<(), WallpaperError> {
+        let refresh_token = oauth::authorize(&config, &self.scheduler, open).await?;
+        config.set_refresh_token(refresh_token);
+        self.set_config(config).await
+    }
+
+    /// The reddit api budget left in the current rate-limit window
+    pub fn rate_budget(&self) -> RateBudget {
+        self.scheduler.budget()
+    }
+
     pub fn is_configured(&self) -> bool {
         self.reddit_client.lock().unwrap().is_some()
     }
 }
+
+/// Path of the `i`th newest backup of the cache at `path`
+fn backup_path(path: &Path, i: usize) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".{i}"));
+    backup.into()
+}
+
+/// Images the downloader saved in `path` as image name and file name, oldest first
+/// Partial downloads and files that don't belong to a post are left out
+fn scan_wallpaper_dir(path: &Path) -> io::Result<Vec<(String, String)>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let file_name = match entry.file_name().into_string() {
+            Ok(file_name) => file_name,
+            Err(_) => continue,
+        };
+        let (name, extension) = match file_name.rsplit_once('.') {
+            Some(parts) => parts,
+            None => continue,
+        };
+        let is_image = VALID_EXTENSION.contains(&extension.to_ascii_lowercase().as_str());
+        if !is_image || !name.starts_with("t3_") {
+            continue;
+        }
+        let modified = entry.metadata()?.modified()?;
+        files.push((modified, name.to_owned(), file_name));
+    }
+    files.sort();
+    Ok(files
+        .into_iter()
+        .map(|(_, name, file_name)| (name, file_name))
+        .collect())
+}
+
+/// Fullname of the post the image `name` belongs to, see `Image::name`
+fn post_name(name: &str) -> &str {
+    match name.match_indices('_').nth(1) {
+        Some((gallery_item, _)) => &name[..gallery_item],
+        None => name,
+    }
+}
+
+/// Failures for all images of a `post` that was skipped for `reason`
+fn rejections(post: &Post, reason: &str) -> Vec<FailedDownload> {
+    post.images
+        .iter()
+        .map(|image| FailedDownload::new(post, image.clone(), reason.to_owned(), true))
+        .collect()
+}
+
+/// Clears the running fetch when it ends, even if it panics or is dropped
+struct InFlightGuard<'a>(&'a Mutex<Option<FetchResult>>);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+}
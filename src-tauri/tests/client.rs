@@ -0,0 +1,299 @@
+mod common;
+
+use common::{link, FakeReddit, USERNAME};
+use reddit_wallpapers::{
+    client::{ClientError, ListingSource, RedditClient, RequestScheduler, Sort, TimeRange},
+    Config, Image, Post,
+};
+use serde_json::json;
+use std::{sync::Arc, time::Instant};
+use tempfile::TempDir;
+use tokio_util::sync::CancellationToken;
+
+async fn client(reddit: &FakeReddit) -> RedditClient {
+    RedditClient::new(&reddit.config("".into()), Arc::new(RequestScheduler::new()))
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn login_and_fetch_userdata() {
+    let reddit = FakeReddit::start().await;
+    let user = client(&reddit).await.fetch_userdata().await.unwrap();
+    assert_eq!(user.name, USERNAME);
+}
+
+#[tokio::test]
+async fn login_with_unreachable_auth_fails() {
+    let reddit = FakeReddit::start().await;
+    let mut config: Config = reddit.config("".into());
+    config.endpoints.auth = reddit.url("nothing-here");
+    let client = RedditClient::new(&config, Arc::new(RequestScheduler::new())).await;
+    assert!(matches!(client, Err(ClientError::Http(404))));
+}
+
+#[tokio::test]
+async fn concurrent_requests_share_the_rate_limit_budget() {
+    let reddit = FakeReddit::start().await;
+    let me = reddit.url("api/v1/me");
+    let scheduler = RequestScheduler::new();
+    reddit.set_rate_limit(2., 1);
+    scheduler.send(|client| client.get(&me)).await.unwrap();
+    assert_eq!(scheduler.budget().remaining, Some(2.));
+
+    // only two of the three requests fit into the window
+    let start = Instant::now();
+    let requests = (0..3).map(|_| scheduler.send(|client| client.get(&me)));
+    for response in futures_util::future::join_all(requests).await {
+        response.unwrap();
+    }
+    assert!(start.elapsed().as_millis() >= 900);
+}
+
+#[tokio::test]
+async fn downloads_do_not_take_from_the_rate_limit_budget() {
+    let reddit = FakeReddit::start().await;
+    let image = reddit.add_image("a.png", "image/png", common::png());
+    let scheduler = RequestScheduler::new();
+    reddit.set_rate_limit(1., 30);
+    scheduler
+        .send(|client| client.get(reddit.url("api/v1/me")))
+        .await
+        .unwrap();
+
+    let start = Instant::now();
+    let requests = (0..3).map(|_| scheduler.send_unmetered(|client| client.get(&image)));
+    for response in futures_util::future::join_all(requests).await {
+        response.unwrap();
+    }
+    assert!(start.elapsed().as_secs() < 5);
+    assert_eq!(scheduler.budget().remaining, Some(1.));
+}
+
+#[tokio::test]
+async fn simultaneous_downloads_share_the_bandwidth() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let mut config = reddit.config(dir.path().to_owned());
+    config.downloads.bandwidth = Some(40_000);
+    let client = RedditClient::new(&config, Arc::new(RequestScheduler::new()))
+        .await
+        .unwrap();
+    let posts = ["a", "b"].map(|name| {
+        let mut body = common::png();
+        body.resize(40_000, 0);
+        let url = reddit.add_image(&format!("{name}.png"), "image/png", body);
+        let name = format!("t3_{name}");
+        vec![Arc::new(Post {
+            subreddit: "wallpaper".to_owned(),
+            title: format!("title of {name}"),
+            images: vec![Image {
+                name: name.clone(),
+                url: url.clone(),
+            }],
+            url,
+            name,
+        })]
+    });
+
+    // each download alone would take less than a second
+    let start = Instant::now();
+    let cancel = CancellationToken::new();
+    let (a, b) = tokio::join!(
+        client.downloader_post_images(&posts[0], &cancel),
+        client.downloader_post_images(&posts[1], &cancel),
+    );
+    assert_eq!(a.paths.len() + b.paths.len(), 2);
+    assert!(start.elapsed().as_millis() >= 950);
+}
+
+#[tokio::test]
+async fn saved_listing_is_paginated_until_the_end() {
+    let reddit = FakeReddit::start().await;
+    reddit.set_saved(vec![
+        vec![
+            link("t3_a", "wallpaper", "a.png"),
+            link("t3_b", "wallpaper", "b.png"),
+        ],
+        vec![link("t3_c", "wallpaper", "c.png")],
+        vec![link("t3_d", "wallpaper", "d.png")],
+    ]);
+
+    let (posts, until) = client(&reddit)
+        .await
+        .fetch_saved_until("", &CancellationToken::new())
+        .await
+        .unwrap();
+    let names = posts
+        .iter()
+        .map(|post| post.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["t3_a", "t3_b", "t3_c", "t3_d"]);
+    assert_eq!(until, "t3_a");
+
+    let listing_requests = reddit
+        .requests()
+        .into_iter()
+        .filter(|request| request.starts_with(&format!("/user/{USERNAME}/saved")))
+        .collect::<Vec<_>>();
+    assert_eq!(listing_requests.len(), 3);
+    assert!(listing_requests[2].contains("after=page2"));
+}
+
+#[tokio::test]
+async fn saved_listing_stops_at_until() {
+    let reddit = FakeReddit::start().await;
+    reddit.set_saved(vec![
+        vec![
+            link("t3_new", "wallpaper", "new.png"),
+            link("t3_old", "wallpaper", "old.png"),
+        ],
+        vec![link("t3_older", "wallpaper", "older.png")],
+    ]);
+
+    let (posts, until) = client(&reddit)
+        .await
+        .fetch_saved_until("t3_old", &CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(posts.len(), 1);
+    assert_eq!(posts[0].name, "t3_new");
+    assert_eq!(until, "t3_new");
+    assert!(!reddit
+        .requests()
+        .iter()
+        .any(|request| request.contains("after=page1")));
+}
+
+#[tokio::test]
+async fn saved_listing_skips_comments_and_deleted_posts() {
+    let reddit = FakeReddit::start().await;
+    reddit.set_saved(vec![vec![
+        json!({ "kind": "t1", "data": { "name": "t1_comment", "body": "nice" } }),
+        json!({
+            "kind": "t3",
+            "data": {
+                "name": "t3_deleted",
+                "subreddit": "wallpaper",
+                "url": "deleted.png",
+                "removed_by_category": "deleted"
+            }
+        }),
+        json!({ "kind": "t3", "data": { "name": "t3_no_url", "subreddit": "wallpaper" } }),
+        link("t3_a", "wallpaper", "a.png"),
+    ]]);
+
+    let (posts, until) = client(&reddit)
+        .await
+        .fetch_saved_until("", &CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(posts.len(), 1);
+    assert_eq!(posts[0].name, "t3_a");
+    assert_eq!(posts[0].title, "title of t3_a");
+    // the cursor points to the newest saved item, whatever kind it is
+    assert_eq!(until, "t1_comment");
+}
+
+#[tokio::test]
+async fn cancelled_listing_fails() {
+    let reddit = FakeReddit::start().await;
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", "a.png")]]);
+    let cancel = CancellationToken::new();
+    cancel.cancel();
+
+    let result = client(&reddit).await.fetch_saved_until("", &cancel).await;
+    assert!(matches!(result, Err(ClientError::Cancelled)));
+}
+
+#[tokio::test]
+async fn empty_saved_listing_keeps_until() {
+    let reddit = FakeReddit::start().await;
+    let (posts, until) = client(&reddit)
+        .await
+        .fetch_saved_until("t3_old", &CancellationToken::new())
+        .await
+        .unwrap();
+    assert!(posts.is_empty());
+    assert_eq!(until, "t3_old");
+}
+
+#[tokio::test]
+async fn listing_sources_map_to_api_paths() {
+    let reddit = FakeReddit::start().await;
+    let sources = [
+        (ListingSource::Upvoted, format!("/user/{USERNAME}/upvoted")),
+        (
+            ListingSource::Subreddit {
+                name: "wallpaper".to_owned(),
+                sort: Sort::Hot,
+            },
+            "/r/wallpaper/hot".to_owned(),
+        ),
+        (
+            ListingSource::Multireddit {
+                user: "someone".to_owned(),
+                name: "walls".to_owned(),
+                sort: Sort::New,
+            },
+            "/user/someone/m/walls/new".to_owned(),
+        ),
+    ];
+    for (source, path) in &sources {
+        reddit.set_listing(path, vec![vec![link("t3_a", "wallpaper", "a.png")]]);
+        let page = client(&reddit)
+            .await
+            .fetch_page(source, None)
+            .await
+            .unwrap();
+        assert_eq!(page.posts.len(), 1, "{path}");
+    }
+}
+
+#[tokio::test]
+async fn top_listing_sends_time_range_and_cursor() {
+    let reddit = FakeReddit::start().await;
+    reddit.set_listing(
+        "/r/wallpaper/top",
+        vec![
+            vec![link("t3_a", "wallpaper", "a.png")],
+            vec![link("t3_b", "wallpaper", "b.png")],
+        ],
+    );
+    let source = ListingSource::Subreddit {
+        name: "wallpaper".to_owned(),
+        sort: Sort::Top(TimeRange::Week),
+    };
+
+    let client = client(&reddit).await;
+    let first = client.fetch_page(&source, None).await.unwrap();
+    assert_eq!(first.after.as_deref(), Some("page1"));
+    let second = client
+        .fetch_page(&source, first.after.as_deref())
+        .await
+        .unwrap();
+    assert_eq!(second.posts[0].name, "t3_b");
+    assert_eq!(second.after, None);
+
+    let requests = reddit.requests();
+    assert!(requests
+        .iter()
+        .any(|request| request.contains("t=week") && request.contains("after=page1")));
+}
+
+#[test]
+fn listing_source_serialization() {
+    let source: ListingSource = serde_json::from_value(json!({
+        "kind": "subreddit",
+        "name": "EarthPorn",
+        "sort": { "by": "top", "t": "month" }
+    }))
+    .unwrap();
+    assert_eq!(
+        source,
+        ListingSource::Subreddit {
+            name: "EarthPorn".to_owned(),
+            sort: Sort::Top(TimeRange::Month),
+        }
+    );
+}
@@ -0,0 +1,300 @@
+//! In-process stand-in for the parts of the reddit api the app uses
+
+#![allow(dead_code)]
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use image::{DynamicImage, ImageOutputFormat};
+use reddit_wallpapers::{Config, Endpoints};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    io::Cursor,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+pub const USERNAME: &str = "tester";
+pub const TOKEN: &str = "test-token";
+pub const REFRESH_TOKEN: &str = "test-refresh-token";
+
+#[derive(Default)]
+struct State {
+    /// Pages of listings by path, linked through `after` cursors
+    listings: HashMap<String, Vec<Vec<Value>>>,
+    /// Maps request paths to content-type and body
+    images: HashMap<String, (String, Vec<u8>)>,
+    /// Every request the server received as path and query
+    requests: Vec<String>,
+    /// Requests left and seconds until the reset, sent with every response
+    rate_limit: Option<(f32, u64)>,
+}
+
+pub struct FakeReddit {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeReddit {
+    /// Starts the server on a random local port
+    pub async fn start() -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let state = state.clone();
+                    async move { Ok::<_, Infallible>(handle(&state, req)) }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        Self { addr, state }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}/{}", self.addr, path.trim_start_matches('/'))
+    }
+
+    /// Config that points all endpoints at this server
+    pub fn config(&self, path: PathBuf) -> Config {
+        let mut config = Config::new(USERNAME, "hunter2", "client-id", "client-secret", path);
+        config.endpoints = Endpoints {
+            auth: self.url(""),
+            api: self.url(""),
+        };
+        config
+    }
+
+    /// Replaces the saved listing, each inner vec is served as one page
+    pub fn set_saved(&self, pages: Vec<Vec<Value>>) {
+        self.set_listing(&format!("/user/{USERNAME}/saved"), pages);
+    }
+
+    /// Serves a listing at `path`, each inner vec is served as one page
+    pub fn set_listing(&self, path: &str, pages: Vec<Vec<Value>>) {
+        self.state
+            .lock()
+            .unwrap()
+            .listings
+            .insert(path.to_owned(), pages);
+    }
+
+    /// Serves `body` at `/images/{name}` and returns its url
+    pub fn add_image(&self, name: &str, content_type: &str, body: Vec<u8>) -> String {
+        let path = format!("/images/{name}");
+        self.state
+            .lock()
+            .unwrap()
+            .images
+            .insert(path.clone(), (content_type.to_owned(), body));
+        self.url(&path)
+    }
+
+    /// Reports the rate-limit budget with every response from now on
+    pub fn set_rate_limit(&self, remaining: f32, reset_in: u64) {
+        self.state.lock().unwrap().rate_limit = Some((remaining, reset_in));
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Clears the request log
+    pub fn reset_requests(&self) {
+        self.state.lock().unwrap().requests.clear();
+    }
+}
+
+/// A saved link as it appears in a listing
+pub fn link(name: &str, subreddit: &str, url: &str) -> Value {
+    json!({
+        "kind": "t3",
+        "data": {
+            "name": name,
+            "subreddit": subreddit,
+            "title": format!("title of {name}"),
+            "url": url,
+        }
+    })
+}
+
+/// A saved gallery, `items` are pairs of media id and image url
+pub fn gallery(name: &str, subreddit: &str, items: &[(&str, &str)]) -> Value {
+    let media_metadata = items
+        .iter()
+        .map(|(media_id, url)| {
+            let url = url.replace('&', "&amp;");
+            (
+                media_id.to_string(),
+                json!({ "status": "valid", "e": "Image", "m": "image/png", "s": { "u": url } }),
+            )
+        })
+        .collect::<serde_json::Map<_, _>>();
+    let gallery_items = items
+        .iter()
+        .map(|(media_id, _)| json!({ "media_id": media_id }))
+        .collect::<Vec<_>>();
+    json!({
+        "kind": "t3",
+        "data": {
+            "name": name,
+            "subreddit": subreddit,
+            "title": format!("title of {name}"),
+            "url": format!("https://www.reddit.com/gallery/{name}"),
+            "is_gallery": true,
+            "gallery_data": { "items": gallery_items },
+            "media_metadata": media_metadata,
+        }
+    })
+}
+
+pub fn png() -> Vec<u8> {
+    encode(ImageOutputFormat::Png)
+}
+
+pub fn jpeg() -> Vec<u8> {
+    encode(ImageOutputFormat::Jpeg(80))
+}
+
+fn encode(format: ImageOutputFormat) -> Vec<u8> {
+    let mut bytes = vec![];
+    DynamicImage::new_rgb8(8, 4)
+        .write_to(&mut Cursor::new(&mut bytes), format)
+        .unwrap();
+    bytes
+}
+
+fn handle(state: &Mutex<State>, req: Request<Body>) -> Response<Body> {
+    let mut state = state.lock().unwrap();
+    state.requests.push(req.uri().to_string());
+    let mut response = respond(&state, req);
+    if let Some((remaining, reset_in)) = state.rate_limit {
+        let headers = response.headers_mut();
+        headers.insert(
+            "x-ratelimit-remaining",
+            remaining.to_string().parse().unwrap(),
+        );
+        headers.insert("x-ratelimit-reset", reset_in.to_string().parse().unwrap());
+    }
+    response
+}
+
+fn respond(state: &State, req: Request<Body>) -> Response<Body> {
+    let path = req.uri().path();
+    if path == "/api/v1/access_token" {
+        return json_response(&json!({
+            "access_token": TOKEN,
+            "expires_in": 3600,
+            "refresh_token": REFRESH_TOKEN,
+        }));
+    }
+    if let Some((content_type, body)) = state.images.get(path) {
+        let range_start = req
+            .headers()
+            .get("range")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| {
+                value
+                    .strip_prefix("bytes=")?
+                    .strip_suffix('-')?
+                    .parse()
+                    .ok()
+            });
+        let response = Response::builder().header("content-type", content_type.as_str());
+        return match range_start {
+            Some(start) if start >= body.len() => status(StatusCode::RANGE_NOT_SATISFIABLE),
+            Some(start) => response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(
+                    "content-range",
+                    format!("bytes {start}-{}/{}", body.len() - 1, body.len()),
+                )
+                .body(Body::from(body[start..].to_vec()))
+                .unwrap(),
+            None => response.body(Body::from(body.clone())).unwrap(),
+        };
+    }
+
+    let authorized = req
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        == Some(&format!("bearer {TOKEN}"));
+    if !authorized {
+        return status(StatusCode::UNAUTHORIZED);
+    }
+
+    if path == "/api/v1/me" {
+        return json_response(&json!({ "id": "abc", "name": USERNAME }));
+    }
+    if path == "/api/info" {
+        let ids = req
+            .uri()
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("id="))
+            .unwrap_or_default()
+            .replace("%2C", ",");
+        let ids = ids.split(',').collect::<Vec<_>>();
+        let children = state
+            .listings
+            .values()
+            .flatten()
+            .flatten()
+            .filter(|child| ids.contains(&child["data"]["name"].as_str().unwrap_or_default()))
+            .cloned()
+            .collect::<Vec<_>>();
+        return json_response(&json!({
+            "kind": "Listing",
+            "data": { "after": null, "children": children }
+        }));
+    }
+    // nothing saved yet
+    let empty = vec![];
+    let saved = (path == format!("/user/{USERNAME}/saved")).then_some(&empty);
+    if let Some(pages) = state.listings.get(path).or(saved) {
+        let after = req
+            .uri()
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("after="));
+        let page = match after {
+            None => 0,
+            Some(cursor) => match cursor.strip_prefix("page").and_then(|n| n.parse().ok()) {
+                Some(page) => page,
+                None => return status(StatusCode::BAD_REQUEST),
+            },
+        };
+        let children = pages.get(page).cloned().unwrap_or_default();
+        let after = (page + 1 < pages.len()).then(|| format!("page{}", page + 1));
+        return json_response(&json!({
+            "kind": "Listing",
+            "data": { "after": after, "children": children }
+        }));
+    }
+    status(StatusCode::NOT_FOUND)
+}
+
+fn json_response(value: &Value) -> Response<Body> {
+    Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(value.to_string()))
+        .unwrap()
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
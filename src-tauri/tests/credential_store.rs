@@ -0,0 +1,83 @@
+use reddit_wallpapers::{
+    credential_store::{CredentialError, CredentialStore, Secrets},
+    Config,
+};
+use std::fs;
+use tempfile::TempDir;
+
+fn secrets() -> Secrets {
+    Secrets {
+        password: "hunter2".to_owned(),
+        client_secret: "client-secret".to_owned(),
+        refresh_token: Some("refresh-token".to_owned()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn missing_store_has_no_secrets() {
+    let dir = TempDir::new().unwrap();
+    let store = CredentialStore::new(dir.path());
+    assert_eq!(store.load(None).unwrap(), Secrets::default());
+}
+
+#[test]
+fn key_file_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let store = CredentialStore::new(dir.path());
+    store.save(&secrets(), None).unwrap();
+
+    assert!(!store.needs_passphrase().unwrap());
+    assert_eq!(store.load(None).unwrap(), secrets());
+    let stored = fs::read_to_string(dir.path().join("credentials.json")).unwrap();
+    assert!(!stored.contains("hunter2"));
+}
+
+#[test]
+fn passphrase_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let store = CredentialStore::new(dir.path());
+    store.save(&secrets(), Some("correct horse")).unwrap();
+
+    assert!(store.needs_passphrase().unwrap());
+    assert!(matches!(store.load(None), Err(CredentialError::Locked)));
+    assert!(matches!(
+        store.load(Some("battery staple")),
+        Err(CredentialError::Decryption)
+    ));
+    assert_eq!(store.load(Some("correct horse")).unwrap(), secrets());
+}
+
+#[test]
+fn plaintext_config_is_migrated() {
+    let dir = TempDir::new().unwrap();
+    let store = CredentialStore::new(dir.path());
+    let config = Config::new("tester", "hunter2", "id", "client-secret", "".into());
+
+    assert!(store.migrate_plaintext(&config, None).unwrap());
+    let stored = store.load(None).unwrap();
+    assert_eq!(stored.password, "hunter2");
+    assert_eq!(stored.client_secret, "client-secret");
+
+    let written = toml::to_string(&config.without_secrets()).unwrap();
+    assert!(!written.contains("hunter2"));
+    assert!(!written.contains("client-secret"));
+    assert!(!store
+        .migrate_plaintext(&config.without_secrets(), None)
+        .unwrap());
+}
+
+#[test]
+fn secrets_of_inactive_profiles_are_kept() {
+    let mut config = Config::new("tester", "hunter2", "id", "client-secret", "".into());
+    config.add_profile("second").unwrap();
+    assert_eq!(config.profile_names(), ["second", "default"]);
+
+    let secrets = config.secrets();
+    assert_eq!(secrets.profiles["default"].password, "hunter2");
+    let mut restored = config.without_secrets();
+    restored.set_secrets(secrets);
+    restored.switch_profile("default").unwrap();
+    assert_eq!(restored.secrets().password, "hunter2");
+    assert!(restored.add_profile("second").is_err());
+}
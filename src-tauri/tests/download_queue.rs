@@ -0,0 +1,52 @@
+use reddit_wallpapers::{download_queue::DownloadQueue, DownloadLimits};
+use std::time::Duration;
+use tokio::time::{timeout, Instant};
+
+fn queue(concurrency: usize, per_host: usize, bandwidth: Option<u64>) -> DownloadQueue {
+    DownloadQueue::new(&DownloadLimits {
+        concurrency,
+        per_host,
+        bandwidth,
+    })
+}
+
+#[tokio::test]
+async fn hosts_are_limited_separately() {
+    let queue = queue(2, 1, None);
+    let _a = queue.acquire("https://i.redd.it/a.png").await;
+    let busy = timeout(
+        Duration::from_millis(50),
+        queue.acquire("https://i.redd.it/b.png"),
+    )
+    .await;
+    assert!(busy.is_err());
+    let other_host = timeout(
+        Duration::from_millis(50),
+        queue.acquire("https://i.imgur.com/c.png"),
+    )
+    .await;
+    assert!(other_host.is_ok());
+}
+
+#[tokio::test]
+async fn concurrency_is_limited_across_hosts() {
+    let queue = queue(1, 4, None);
+    let slot = queue.acquire("https://i.redd.it/a.png").await;
+    let busy = timeout(
+        Duration::from_millis(50),
+        queue.acquire("https://i.imgur.com/b.png"),
+    )
+    .await;
+    assert!(busy.is_err());
+    drop(slot);
+    queue.acquire("https://i.imgur.com/b.png").await;
+}
+
+#[tokio::test]
+async fn bandwidth_is_capped() {
+    let queue = queue(1, 1, Some(10_000));
+    let start = Instant::now();
+    queue.consume(1_000).await;
+    queue.consume(1_000).await;
+    assert!(start.elapsed() >= Duration::from_millis(100));
+}
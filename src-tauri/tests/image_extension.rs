@@ -0,0 +1,73 @@
+mod common;
+
+use common::{jpeg, png};
+use reddit_wallpapers::{image_extension, WallpaperError};
+
+#[test]
+fn magic_bytes_win_over_header_and_url() {
+    assert_eq!(
+        image_extension(&png(), Some("image/jpeg"), "https://i.redd.it/a.jpg").unwrap(),
+        "png"
+    );
+    assert_eq!(
+        image_extension(&jpeg(), None, "https://i.redd.it/a").unwrap(),
+        "jpeg"
+    );
+}
+
+#[test]
+fn header_and_url_are_fallbacks() {
+    let unknown = b"\x00\x01\x02\x03";
+    assert_eq!(
+        image_extension(unknown, Some("image/bmp"), "https://i.redd.it/a").unwrap(),
+        "bmp"
+    );
+    assert_eq!(
+        image_extension(unknown, None, "https://i.redd.it/a.RAW").unwrap(),
+        "raw"
+    );
+    assert!(matches!(
+        image_extension(unknown, None, "https://i.redd.it/a"),
+        Err(WallpaperError::InvalidEnding)
+    ));
+}
+
+#[test]
+fn html_is_rejected() {
+    let page = b"\n  <!DOCTYPE html><html><body>not found</body></html>";
+    assert!(matches!(
+        image_extension(page, Some("image/jpeg"), "https://i.redd.it/a.jpg"),
+        Err(WallpaperError::NotAnImage(_))
+    ));
+    assert!(matches!(
+        image_extension(&png(), Some("text/html"), "https://i.redd.it/a.png"),
+        Err(WallpaperError::NotAnImage(_))
+    ));
+}
+
+#[test]
+fn unsupported_formats_are_rejected() {
+    let webp = b"RIFF\x00\x00\x00\x00WEBPVP8 ";
+    assert!(matches!(
+        image_extension(webp, Some("image/jpeg"), "https://i.redd.it/a.jpg"),
+        Err(WallpaperError::NotAnImage(_))
+    ));
+}
+
+#[test]
+fn other_content_types_are_rejected() {
+    let error = br#"{"error": 404}"#;
+    assert!(matches!(
+        image_extension(error, Some("application/json"), "https://i.redd.it/a.jpg"),
+        Err(WallpaperError::NotAnImage(_))
+    ));
+    assert_eq!(
+        image_extension(
+            b"\x00\x01",
+            Some("application/octet-stream"),
+            "https://i.redd.it/a.jpg"
+        )
+        .unwrap(),
+        "jpg"
+    );
+}
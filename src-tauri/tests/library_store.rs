@@ -0,0 +1,164 @@
+use reddit_wallpapers::{
+    client::FailedDownload,
+    library_store::{LibraryChanges, LibraryStore, SqliteStore},
+    wallpaper_manager::Wallpaper,
+    Image, Post,
+};
+use std::{collections::HashMap, sync::Arc};
+use tempfile::TempDir;
+
+fn wallpaper(name: &str, subreddit: &str) -> Arc<Wallpaper> {
+    Arc::new(Wallpaper {
+        subreddit: subreddit.to_owned(),
+        title: format!("title of {name}"),
+        url: format!("https://i.redd.it/{name}.png"),
+        name: name.to_owned(),
+        file_name: format!("{name}.png"),
+        post_name: name.to_owned(),
+        profile: "default".to_owned(),
+        format: "png".to_owned(),
+    })
+}
+
+fn add(store: &SqliteStore, wallpapers: &[Arc<Wallpaper>], cursors: &HashMap<String, String>) {
+    store
+        .save(&LibraryChanges {
+            wallpapers,
+            post_data: &HashMap::new(),
+            cursors,
+            failed_downloads: &[],
+        })
+        .unwrap();
+}
+
+fn names(wallpapers: &[Wallpaper]) -> Vec<&str> {
+    wallpapers.iter().map(|wp| wp.name.as_str()).collect()
+}
+
+#[test]
+fn saves_are_incremental_and_persist() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("library.sqlite");
+    let cursors = HashMap::from([("default".to_owned(), "t3_a".to_owned())]);
+
+    let store = SqliteStore::open(&path).unwrap();
+    assert!(store.is_empty().unwrap());
+    add(&store, &[wallpaper("t3_a", "wallpaper")], &cursors);
+    add(&store, &[wallpaper("t3_b", "wallpaper")], &cursors);
+    drop(store);
+
+    let store = SqliteStore::open(&path).unwrap();
+    assert!(!store.is_empty().unwrap());
+    assert_eq!(names(&store.wallpapers().unwrap()), ["t3_a", "t3_b"]);
+    assert_eq!(store.load().unwrap().cursors, cursors);
+}
+
+#[test]
+fn lookups_by_name_subreddit_and_tag() {
+    let store = SqliteStore::in_memory().unwrap();
+    let wallpapers = [
+        wallpaper("t3_a", "wallpaper"),
+        wallpaper("t3_b", "EarthPorn"),
+        wallpaper("t3_c", "wallpaper"),
+    ];
+    add(&store, &wallpapers, &HashMap::new());
+
+    assert_eq!(
+        store.wallpaper("t3_b").unwrap().unwrap().subreddit,
+        "EarthPorn"
+    );
+    assert!(store.wallpaper("t3_x").unwrap().is_none());
+    assert!(store.has_post("t3_a").unwrap());
+    assert!(!store.has_post("t3_x").unwrap());
+    let wallpaper = store.wallpapers_of_subreddit("Wallpaper").unwrap();
+    assert_eq!(names(&wallpaper), ["t3_a", "t3_c"]);
+
+    store
+        .set_tags("t3_c", &["dark".to_owned(), "city".to_owned()])
+        .unwrap();
+    store.set_tags("t3_b", &["dark".to_owned()]).unwrap();
+    assert_eq!(
+        names(&store.wallpapers_tagged("dark").unwrap()),
+        ["t3_b", "t3_c"]
+    );
+    assert_eq!(store.tags("t3_c").unwrap(), ["city", "dark"]);
+
+    store.set_tags("t3_c", &["city".to_owned()]).unwrap();
+    assert_eq!(names(&store.wallpapers_tagged("dark").unwrap()), ["t3_b"]);
+    // tags belong to wallpapers of the library
+    assert!(store.set_tags("t3_x", &["dark".to_owned()]).is_err());
+}
+
+#[test]
+fn changed_wallpapers_keep_their_position() {
+    let store = SqliteStore::in_memory().unwrap();
+    add(
+        &store,
+        &[
+            wallpaper("t3_a", "wallpaper"),
+            wallpaper("t3_b", "wallpaper"),
+        ],
+        &HashMap::new(),
+    );
+    let renamed = Arc::new(Wallpaper {
+        title: "new title".to_owned(),
+        ..(*wallpaper("t3_a", "wallpaper")).clone()
+    });
+    add(&store, &[renamed], &HashMap::new());
+
+    let wallpapers = store.wallpapers().unwrap();
+    assert_eq!(names(&wallpapers), ["t3_a", "t3_b"]);
+    assert_eq!(wallpapers[0].title, "new title");
+}
+
+fn failure(name: &str, error: &str) -> FailedDownload {
+    let post = Post {
+        subreddit: "wallpaper".to_owned(),
+        title: format!("title of {name}"),
+        url: format!("https://example.com/{name}"),
+        name: name.to_owned(),
+        images: vec![],
+    };
+    let image = Image {
+        name: name.to_owned(),
+        url: post.url.clone(),
+    };
+    FailedDownload::new(&post, image, error.to_owned(), false)
+}
+
+#[test]
+fn cursors_and_failures_are_replaced_by_the_saved_ones() {
+    let store = SqliteStore::in_memory().unwrap();
+    let save = |cursors: &HashMap<String, String>, failed_downloads: &[FailedDownload]| {
+        store
+            .save(&LibraryChanges {
+                wallpapers: &[],
+                post_data: &HashMap::new(),
+                cursors,
+                failed_downloads,
+            })
+            .unwrap()
+    };
+    save(
+        &HashMap::from([
+            ("default".to_owned(), "t3_a".to_owned()),
+            ("work".to_owned(), "t3_b".to_owned()),
+        ]),
+        &[failure("t3_x", "timeout"), failure("t3_y", "timeout")],
+    );
+    let cursors = HashMap::from([("default".to_owned(), "t3_c".to_owned())]);
+    save(
+        &cursors,
+        &[failure("t3_y", "not an image"), failure("t3_z", "timeout")],
+    );
+
+    let library = store.load().unwrap();
+    assert_eq!(library.cursors, cursors);
+    let failed = library
+        .failed_downloads
+        .iter()
+        .map(|failed| (failed.image.name.as_str(), failed.error.as_str()))
+        .collect::<Vec<_>>();
+    // a changed failure keeps its position
+    assert_eq!(failed, [("t3_y", "not an image"), ("t3_z", "timeout")]);
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use common::{link, FakeReddit, REFRESH_TOKEN};
+use reddit_wallpapers::{
+    client::{ListingSource, RedditClient, RequestScheduler},
+    oauth::{authorize, Pkce},
+    Config,
+};
+use reqwest::Url;
+use std::{collections::HashMap, sync::Arc};
+
+#[test]
+fn pkce_challenge_matches_rfc_example() {
+    let pkce = Pkce::from_verifier("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".to_owned());
+    assert_eq!(
+        pkce.challenge,
+        "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+    );
+}
+
+#[tokio::test]
+async fn authorization_code_flow_returns_refresh_token() {
+    let reddit = FakeReddit::start().await;
+    let mut config = reddit.config("".into());
+    config.redirect_port = Some(0);
+
+    let refresh_token = authorize(&config, &RequestScheduler::new(), |url| {
+        let url = Url::parse(url).unwrap();
+        let params = url.query_pairs().into_owned().collect::<HashMap<_, _>>();
+        assert_eq!(params["response_type"], "code");
+        assert_eq!(params["code_challenge_method"], "S256");
+        assert!(!params["code_challenge"].is_empty());
+
+        // play the browser that gets redirected after the user agreed
+        let mut redirect = Url::parse(&params["redirect_uri"]).unwrap();
+        redirect
+            .query_pairs_mut()
+            .append_pair("state", &params["state"])
+            .append_pair("code", "abc");
+        tokio::spawn(async move { reqwest::get(redirect).await.unwrap() });
+        Ok(())
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(refresh_token, REFRESH_TOKEN);
+}
+
+#[tokio::test]
+async fn authorization_with_wrong_state_fails() {
+    let reddit = FakeReddit::start().await;
+    let mut config = reddit.config("".into());
+    config.redirect_port = Some(0);
+
+    let result = authorize(&config, &RequestScheduler::new(), |url| {
+        let url = Url::parse(url).unwrap();
+        let params = url.query_pairs().into_owned().collect::<HashMap<_, _>>();
+        let mut redirect = Url::parse(&params["redirect_uri"]).unwrap();
+        redirect
+            .query_pairs_mut()
+            .append_pair("state", "forged")
+            .append_pair("code", "abc");
+        tokio::spawn(async move { reqwest::get(redirect).await.unwrap() });
+        Ok(())
+    })
+    .await;
+
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn client_logs_in_with_refresh_token() {
+    let reddit = FakeReddit::start().await;
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", "a.png")]]);
+    let mut config = Config::new("", "", "client-id", "", "".into());
+    config.endpoints = reddit.config("".into()).endpoints;
+    config.set_refresh_token(REFRESH_TOKEN.to_owned());
+
+    let client = RedditClient::new(&config, Arc::new(RequestScheduler::new()))
+        .await
+        .unwrap();
+    // the username is looked up because the config doesn't know it
+    let page = client
+        .fetch_page(&ListingSource::Saved, None)
+        .await
+        .unwrap();
+    assert_eq!(page.posts.len(), 1);
+}
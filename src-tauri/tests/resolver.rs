@@ -0,0 +1,85 @@
+use reddit_wallpapers::{
+    client::RequestScheduler,
+    resolver::{album_images, og_image, Resolvers},
+    Config, Image, Post,
+};
+
+fn post(url: &str) -> Post {
+    Post {
+        subreddit: "wallpaper".to_owned(),
+        title: "title".to_owned(),
+        url: url.to_owned(),
+        name: "t3_a".to_owned(),
+        images: vec![Image {
+            name: "t3_a".to_owned(),
+            url: url.to_owned(),
+        }],
+    }
+}
+
+async fn resolve(url: &str) -> Vec<Image> {
+    let mut post = post(url);
+    Resolvers::new(&Config::default())
+        .resolve(&mut post, &RequestScheduler::new())
+        .await;
+    post.images
+}
+
+#[test]
+fn og_image_of_imgur_page() {
+    let html = include_str!("fixtures/imgur_single.html");
+    assert_eq!(
+        og_image(html).as_deref(),
+        Some("https://i.imgur.com/Xk3fWz9.jpeg")
+    );
+}
+
+#[test]
+fn og_image_missing() {
+    assert_eq!(
+        og_image("<html><head><title>404</title></head></html>"),
+        None
+    );
+}
+
+#[test]
+fn images_of_imgur_album() {
+    let json = include_str!("fixtures/imgur_album.json");
+    assert_eq!(
+        album_images(json).unwrap(),
+        [
+            "https://i.imgur.com/a8Fh2Kd.png",
+            "https://i.imgur.com/pL0wQ3z.jpeg",
+            "https://i.imgur.com/Zr9mN1c.jpeg",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn reddit_preview_is_resolved_to_original() {
+    let images = resolve("https://preview.redd.it/yw43o8cxhwm81.jpg?width=640&s=abc").await;
+    assert_eq!(images[0].url, "https://i.redd.it/yw43o8cxhwm81.jpg");
+    assert_eq!(images[0].name, "t3_a");
+}
+
+#[tokio::test]
+async fn reddit_media_redirect_is_unwrapped() {
+    let images =
+        resolve("https://www.reddit.com/media?url=https%3A%2F%2Fi.redd.it%2Fabc.png").await;
+    assert_eq!(images[0].url, "https://i.redd.it/abc.png");
+}
+
+#[tokio::test]
+async fn imgur_link_with_extension_gets_subdomain() {
+    let images = resolve("https://imgur.com/Xk3fWz9.png").await;
+    assert_eq!(images[0].url, "https://i.imgur.com/Xk3fWz9.png");
+}
+
+#[tokio::test]
+async fn unknown_hosts_are_kept() {
+    let images = resolve("https://example.com/wallpaper.png").await;
+    assert_eq!(
+        images,
+        [post("https://example.com/wallpaper.png").images[0].clone()]
+    );
+}
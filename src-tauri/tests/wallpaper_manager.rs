@@ -0,0 +1,972 @@
+mod common;
+
+use common::{gallery, jpeg, link, png, FakeReddit, REFRESH_TOKEN, USERNAME};
+use reddit_wallpapers::{
+    cache_schema::CacheError,
+    client::{ClientError, ListingSource, Sort},
+    credential_store::{CredentialError, CredentialStore},
+    library_store::SqliteStore,
+    progress::FetchEvent,
+    wallpaper_manager::WallpaperManager,
+    Config, Subreddits, WallpaperError,
+};
+use tempfile::TempDir;
+
+async fn manager(reddit: &FakeReddit, dir: &TempDir) -> WallpaperManager {
+    let wm = WallpaperManager::with_config(reddit.config(dir.path().to_owned())).await;
+    assert!(wm.is_configured());
+    wm
+}
+
+#[tokio::test]
+async fn fetch_recent_downloads_images_and_thumbnails() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let a = reddit.add_image("a.png", "image/png", png());
+    let b = reddit.add_image("b.jpg", "image/jpeg", jpeg());
+    reddit.set_saved(vec![
+        vec![link("t3_a", "wallpaper", &a)],
+        vec![link("t3_b", "wallpaper", &b)],
+    ]);
+
+    let wm = manager(&reddit, &dir).await;
+    wm.fetch_recent_wallpapers().await.unwrap();
+
+    let wallpapers = wm.get_cached_wallpapers().await.unwrap();
+    let mut file_names = wallpapers
+        .iter()
+        .map(|wp| wp.file_name.as_str())
+        .collect::<Vec<_>>();
+    file_names.sort_unstable();
+    assert_eq!(file_names, ["t3_a.png", "t3_b.jpeg"]);
+    assert!(wallpapers.iter().all(|wp| wp.profile == "default"));
+    for file_name in file_names {
+        assert!(dir.path().join(file_name).is_file());
+        assert!(dir.path().join("thumbnails").join(file_name).is_file());
+    }
+}
+
+#[tokio::test]
+async fn fetch_recent_reports_progress() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let a = reddit.add_image("a.png", "image/png", png());
+    let missing = reddit.url("/images/missing.png");
+    reddit.set_saved(vec![vec![
+        link("t3_a", "wallpaper", &a),
+        link("t3_b", "wallpaper", &missing),
+    ]]);
+
+    let wm = manager(&reddit, &dir).await;
+    let mut events = wm.subscribe();
+    wm.fetch_recent_wallpapers().await.unwrap();
+
+    let events = std::iter::from_fn(|| events.try_recv().ok()).collect::<Vec<_>>();
+    assert_eq!(
+        events[0],
+        FetchEvent::ListingPage {
+            posts: 2,
+            after: None
+        }
+    );
+    assert!(events.contains(&FetchEvent::Download {
+        image: "t3_a".to_owned(),
+        downloaded: png().len() as u64,
+        total: Some(png().len() as u64),
+    }));
+    assert!(events.contains(&FetchEvent::Thumbnail {
+        image: "t3_a".to_owned()
+    }));
+    assert_eq!(
+        events.last(),
+        Some(&FetchEvent::Finished {
+            posts: 2,
+            images: 1,
+            failed: 1
+        })
+    );
+}
+
+#[tokio::test]
+async fn retry_during_a_fetch_does_not_prevent_its_cancel() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let a = reddit.add_image("a.png", "image/png", png());
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", &a)]]);
+
+    let wm = manager(&reddit, &dir).await;
+    let mut events = wm.subscribe();
+    let retry_then_cancel = async {
+        while let Ok(event) = events.recv().await {
+            match event {
+                FetchEvent::ListingPage { .. } => wm.retry_failed_downloads(&[]).await.unwrap(),
+                FetchEvent::PostQueued { .. } => {
+                    wm.cancel_fetch();
+                    break;
+                }
+                _ => {}
+            }
+        }
+    };
+    let (result, _) = tokio::join!(wm.fetch_recent_wallpapers(), retry_then_cancel);
+
+    assert!(matches!(result, Err(ClientError::Cancelled)));
+    assert!(wm.get_cached_wallpapers().await.unwrap().is_empty());
+    // later operations are not cancelled
+    wm.fetch_recent_wallpapers().await.unwrap();
+    assert_eq!(wm.get_cached_wallpapers().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn cancelled_fetch_is_resumed_by_the_next_one() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let a = reddit.add_image("a.png", "image/png", png());
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", &a)]]);
+
+    let wm = manager(&reddit, &dir).await;
+    let mut events = wm.subscribe();
+    let cancel_when_queued = async {
+        while let Ok(event) = events.recv().await {
+            if matches!(event, FetchEvent::PostQueued { .. }) {
+                wm.cancel_fetch();
+                break;
+            }
+        }
+    };
+    let (result, _) = tokio::join!(wm.fetch_recent_wallpapers(), cancel_when_queued);
+
+    assert!(matches!(result, Err(ClientError::Cancelled)));
+    assert!(wm.get_cached_wallpapers().await.unwrap().is_empty());
+    assert!(!dir.path().join("t3_a.png").exists());
+
+    wm.fetch_recent_wallpapers().await.unwrap();
+    assert_eq!(wm.get_cached_wallpapers().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn failed_downloads_are_retried_by_the_next_fetch() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let url = reddit.url("/images/a.png");
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", &url)]]);
+
+    let wm = manager(&reddit, &dir).await;
+    wm.fetch_recent_wallpapers().await.unwrap();
+    assert!(wm.get_cached_wallpapers().await.unwrap().is_empty());
+
+    reddit.add_image("a.png", "image/png", png());
+    wm.fetch_recent_wallpapers().await.unwrap();
+    let wallpapers = wm.get_cached_wallpapers().await.unwrap();
+    assert_eq!(wallpapers.len(), 1);
+    assert_eq!(wallpapers[0].post_name, "t3_a");
+}
+
+#[tokio::test]
+async fn partial_downloads_are_resumed() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let image = png();
+    let a = reddit.add_image("a.png", "image/png", image.clone());
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", &a)]]);
+    std::fs::write(dir.path().join("t3_a.part"), &image[..10]).unwrap();
+
+    let wm = manager(&reddit, &dir).await;
+    let mut events = wm.subscribe();
+    wm.fetch_recent_wallpapers().await.unwrap();
+
+    assert_eq!(std::fs::read(dir.path().join("t3_a.png")).unwrap(), image);
+    assert!(!dir.path().join("t3_a.part").exists());
+    let events = std::iter::from_fn(|| events.try_recv().ok()).collect::<Vec<_>>();
+    assert!(events.contains(&FetchEvent::Download {
+        image: "t3_a".to_owned(),
+        downloaded: image.len() as u64,
+        total: Some(image.len() as u64),
+    }));
+}
+
+#[tokio::test]
+async fn short_partial_download_is_sniffed_with_the_rest() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let image = png();
+    // too short to hold the png signature, the header alone would say jpeg
+    let a = reddit.add_image("a.jpg", "image/jpeg", image.clone());
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", &a)]]);
+    std::fs::write(dir.path().join("t3_a.part"), &image[..4]).unwrap();
+
+    let wm = manager(&reddit, &dir).await;
+    wm.fetch_recent_wallpapers().await.unwrap();
+
+    assert_eq!(std::fs::read(dir.path().join("t3_a.png")).unwrap(), image);
+    assert_eq!(wm.get_cached_wallpapers().await.unwrap()[0].format, "png");
+}
+
+#[tokio::test]
+async fn oversized_partial_download_starts_over() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let image = png();
+    let a = reddit.add_image("a.png", "image/png", image.clone());
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", &a)]]);
+    std::fs::write(dir.path().join("t3_a.part"), vec![0; image.len() + 5]).unwrap();
+
+    let wm = manager(&reddit, &dir).await;
+    wm.fetch_recent_wallpapers().await.unwrap();
+
+    assert_eq!(std::fs::read(dir.path().join("t3_a.png")).unwrap(), image);
+    assert_eq!(wm.get_cached_wallpapers().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn skipped_posts_are_listed_and_can_be_retried() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let pics = reddit.add_image("pics.png", "image/png", png());
+    reddit.set_saved(vec![vec![link("t3_pics", "pics", &pics)]]);
+
+    let wm = manager(&reddit, &dir).await;
+    wm.fetch_recent_wallpapers().await.unwrap();
+    assert!(wm.get_cached_wallpapers().await.unwrap().is_empty());
+    let failed = wm.failed_downloads();
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].image.name, "t3_pics");
+    assert!(failed[0].rejected);
+    assert!(failed[0].error.contains("r/pics"));
+    assert!(failed[0].failed_at > 0);
+
+    wm.retry_failed_downloads(&["t3_pics".to_owned()])
+        .await
+        .unwrap();
+    assert!(wm.failed_downloads().is_empty());
+    let wallpapers = wm.get_cached_wallpapers().await.unwrap();
+    assert_eq!(wallpapers.len(), 1);
+    assert_eq!(wallpapers[0].subreddit, "pics");
+}
+
+#[tokio::test]
+async fn every_sync_is_saved_to_the_cache() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let cache = dir.path().join("cache.json");
+    let a = reddit.add_image("a.png", "image/png", png());
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", &a)]]);
+
+    let wm = manager(&reddit, &dir).await.with_cache_file(cache.clone());
+    wm.fetch_recent_wallpapers().await.unwrap();
+    assert!(cache.is_file());
+    drop(wm);
+
+    // a restarted app continues after the saved post
+    let wm = manager(&reddit, &dir).await.with_cache_file(cache);
+    assert_eq!(wm.get_cached_wallpapers().await.unwrap().len(), 1);
+    reddit.reset_requests();
+    wm.fetch_recent_wallpapers().await.unwrap();
+    assert_eq!(wm.get_cached_wallpapers().await.unwrap().len(), 1);
+    assert!(!reddit
+        .requests()
+        .iter()
+        .any(|request| request.starts_with("/images/")));
+}
+
+#[tokio::test]
+async fn unreadable_cache_falls_back_to_a_backup() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let cache = dir.path().join("cache.json");
+    let a = reddit.add_image("a.png", "image/png", png());
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", &a)]]);
+
+    let wm = manager(&reddit, &dir).await.with_cache_file(cache.clone());
+    wm.fetch_recent_wallpapers().await.unwrap();
+    wm.save_cache().unwrap();
+    assert!(dir.path().join("cache.json.1").is_file());
+    assert!(!dir.path().join("cache.json.tmp").exists());
+
+    // e.g. the disk filled up while the file was written by an older version
+    std::fs::write(&cache, "{\"post_data\": {").unwrap();
+    let wm = manager(&reddit, &dir).await.with_cache_file(cache);
+    assert_eq!(wm.get_cached_wallpapers().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn unversioned_cache_is_migrated() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let cache = dir.path().join("cache.json");
+    // as written before galleries, profiles and format detection
+    std::fs::write(
+        &cache,
+        r#"{
+            "post_data": {"t3_a": {"selected": true}},
+            "posts": [{
+                "subreddit": "wallpaper",
+                "title": "a",
+                "url": "https://i.redd.it/a.png",
+                "name": "t3_a",
+                "file_name": "t3_a.png"
+            }],
+            "last_seen_wallpaper": "t3_a"
+        }"#,
+    )
+    .unwrap();
+    let a = reddit.add_image("a.png", "image/png", png());
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", &a)]]);
+
+    let wm = manager(&reddit, &dir).await.with_cache_file(cache.clone());
+    assert_eq!(wm.cache_error(), None);
+    let wallpapers = wm.get_cached_wallpapers().await.unwrap();
+    assert_eq!(wallpapers.len(), 1);
+    assert_eq!(wallpapers[0].post_name, "t3_a");
+    assert_eq!(wallpapers[0].profile, "default");
+    assert_eq!(wallpapers[0].format, "png");
+
+    // the cursor was carried over
+    wm.fetch_recent_wallpapers().await.unwrap();
+    assert!(!reddit
+        .requests()
+        .iter()
+        .any(|request| request.starts_with("/images/")));
+    let saved: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&cache).unwrap()).unwrap();
+    assert_eq!(saved["version"], 1);
+}
+
+#[tokio::test]
+async fn cache_of_a_newer_version_is_reported() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let cache = dir.path().join("cache.json");
+    let newer = r#"{"version": 99, "library": []}"#;
+    std::fs::write(&cache, newer).unwrap();
+
+    let wm = manager(&reddit, &dir).await.with_cache_file(cache);
+    assert_eq!(wm.cache_error(), Some(CacheError::Newer(99)));
+    assert!(wm.get_cached_wallpapers().await.unwrap().is_empty());
+    let kept = std::fs::read_to_string(dir.path().join("cache.json.broken")).unwrap();
+    assert_eq!(kept, newer);
+}
+
+#[tokio::test]
+async fn cache_is_imported_into_the_library() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let cache = dir.path().join("cache.json");
+    let database = dir.path().join("library.sqlite");
+    let a = reddit.add_image("a.png", "image/png", png());
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", &a)]]);
+    let wm = manager(&reddit, &dir).await.with_cache_file(cache.clone());
+    wm.fetch_recent_wallpapers().await.unwrap();
+    drop(wm);
+
+    let library = SqliteStore::open(&database).unwrap();
+    let wm = manager(&reddit, &dir).await.with_library(library, &cache);
+    assert_eq!(wm.get_cached_wallpapers().await.unwrap().len(), 1);
+    assert!(!cache.exists());
+    assert!(dir.path().join("cache.json.imported").is_file());
+
+    // new wallpapers are added to the library, and the cursor moves on
+    let b = reddit.add_image("b.png", "image/png", png());
+    reddit.set_saved(vec![vec![
+        link("t3_b", "wallpaper", &b),
+        link("t3_a", "wallpaper", &a),
+    ]]);
+    wm.fetch_recent_wallpapers().await.unwrap();
+    drop(wm);
+
+    let library = SqliteStore::open(&database).unwrap();
+    let wm = manager(&reddit, &dir).await.with_library(library, &cache);
+    let names = wm
+        .get_cached_wallpapers()
+        .await
+        .unwrap()
+        .iter()
+        .map(|wallpaper| wallpaper.name.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["t3_a", "t3_b"]);
+    reddit.reset_requests();
+    wm.fetch_recent_wallpapers().await.unwrap();
+    assert!(!reddit
+        .requests()
+        .iter()
+        .any(|request| request.starts_with("/images/")));
+}
+
+#[tokio::test]
+async fn rescan_rebuilds_a_lost_library() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let a = reddit.add_image("a.png", "image/png", png());
+    let m1 = reddit.add_image("m1.png", "image/png", png());
+    let m2 = reddit.add_image("m2.jpg", "image/jpeg", jpeg());
+    reddit.set_saved(vec![vec![
+        link("t3_a", "wallpaper", &a),
+        gallery("t3_g", "wallpaper", &[("m1", &m1), ("m2", &m2)]),
+    ]]);
+    let wm = manager(&reddit, &dir).await;
+    wm.fetch_recent_wallpapers().await.unwrap();
+    drop(wm);
+
+    let thumbnail = dir.path().join("thumbnails").join("t3_a.png");
+    std::fs::remove_file(&thumbnail).unwrap();
+    std::fs::write(dir.path().join("t3_b.png.part"), png()).unwrap();
+    std::fs::write(dir.path().join("notes.txt"), "not a wallpaper").unwrap();
+
+    // the cache is gone, only the files are left
+    let wm = manager(&reddit, &dir).await;
+    assert_eq!(wm.rescan_library(false).await.unwrap(), 3);
+    let mut wallpapers = wm.get_cached_wallpapers().await.unwrap();
+    wallpapers.sort_by(|a, b| a.name.cmp(&b.name));
+    let names = wallpapers
+        .iter()
+        .map(|wallpaper| (wallpaper.name.as_str(), wallpaper.post_name.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        [("t3_a", "t3_a"), ("t3_g_m1", "t3_g"), ("t3_g_m2", "t3_g")]
+    );
+    assert_eq!(wallpapers[2].format, "jpeg");
+    assert!(wallpapers[0].title.is_empty());
+    assert!(thumbnail.is_file());
+    // a second rescan finds nothing new
+    assert_eq!(wm.rescan_library(false).await.unwrap(), 0);
+
+    let wm = manager(&reddit, &dir).await;
+    reddit.reset_requests();
+    assert_eq!(wm.rescan_library(true).await.unwrap(), 3);
+    let info_requests = reddit
+        .requests()
+        .into_iter()
+        .filter(|request| request.starts_with("/api/info"))
+        .count();
+    assert_eq!(info_requests, 1);
+    let mut wallpapers = wm.get_cached_wallpapers().await.unwrap();
+    wallpapers.sort_by(|a, b| a.name.cmp(&b.name));
+    assert_eq!(wallpapers[0].title, "title of t3_a");
+    assert_eq!(wallpapers[1].subreddit, "wallpaper");
+    assert_eq!(wallpapers[2].url, m2);
+}
+
+#[tokio::test]
+async fn unopenable_library_is_reported_instead_of_the_cache() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let cache = dir.path().join("cache.json");
+    let database = dir.path().join("library.sqlite");
+    let a = reddit.add_image("a.png", "image/png", png());
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", &a)]]);
+    let wm = manager(&reddit, &dir)
+        .await
+        .with_library_file(&database, &cache);
+    wm.fetch_recent_wallpapers().await.unwrap();
+    drop(wm);
+    // e.g. the database was damaged
+    let damaged = "not a database ".repeat(100);
+    std::fs::write(&database, &damaged).unwrap();
+
+    let wm = manager(&reddit, &dir)
+        .await
+        .with_library_file(&database, &cache);
+    assert!(matches!(wm.cache_error(), Some(CacheError::Library(_))));
+    assert!(wm.get_cached_wallpapers().await.unwrap().is_empty());
+    // nothing is written over the damaged library
+    wm.fetch_recent_wallpapers().await.unwrap();
+    assert!(!cache.exists());
+    assert_eq!(std::fs::read_to_string(&database).unwrap(), damaged);
+}
+
+#[tokio::test]
+async fn wallpapers_are_looked_up_in_the_library() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let a = reddit.add_image("a.png", "image/png", png());
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", &a)]]);
+    let wm = manager(&reddit, &dir).await;
+    wm.fetch_recent_wallpapers().await.unwrap();
+    // without a library only the memory is searched, and there are no tags
+    assert!(wm.get_wallpaper("t3_a").unwrap().is_some());
+    assert!(matches!(
+        wm.set_tags("t3_a", &["dark".to_owned()]),
+        Err(WallpaperError::NoLibrary)
+    ));
+
+    let database = dir.path().join("library.sqlite");
+    let wm = manager(&reddit, &dir)
+        .await
+        .with_library_file(&database, &dir.path().join("cache.json"));
+    wm.rescan_library(false).await.unwrap();
+    assert_eq!(
+        wm.get_wallpaper("t3_a").unwrap().unwrap().file_name,
+        "t3_a.png"
+    );
+    assert!(wm.get_wallpaper("t3_x").unwrap().is_none());
+    assert_eq!(wm.wallpapers_of_subreddit("").unwrap().len(), 1);
+
+    wm.set_tags("t3_a", &["dark".to_owned()]).unwrap();
+    assert_eq!(wm.tags("t3_a").unwrap(), ["dark"]);
+    assert_eq!(wm.wallpapers_tagged("dark").unwrap()[0].name, "t3_a");
+    assert!(wm.wallpapers_tagged("light").unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn unsaved_sync_fails() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let a = reddit.add_image("a.png", "image/png", png());
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", &a)]]);
+
+    // a directory can't be written as file
+    let wm = manager(&reddit, &dir)
+        .await
+        .with_cache_file(dir.path().to_owned());
+    let result = wm.fetch_recent_wallpapers().await;
+    assert!(matches!(result, Err(ClientError::Persistence(_))));
+}
+
+#[tokio::test]
+async fn fetch_recent_skips_already_seen_posts() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let a = reddit.add_image("a.png", "image/png", png());
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", &a)]]);
+
+    let wm = manager(&reddit, &dir).await;
+    wm.fetch_recent_wallpapers().await.unwrap();
+    reddit.reset_requests();
+    wm.fetch_recent_wallpapers().await.unwrap();
+
+    assert_eq!(wm.get_cached_wallpapers().await.unwrap().len(), 1);
+    assert!(!reddit
+        .requests()
+        .iter()
+        .any(|request| request.starts_with("/images/")));
+}
+
+#[tokio::test]
+async fn overlapping_fetches_share_one_request() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let a = reddit.add_image("a.png", "image/png", png());
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", &a)]]);
+
+    let wm = manager(&reddit, &dir).await;
+    reddit.reset_requests();
+    let (first, second) = tokio::join!(wm.fetch_recent_wallpapers(), wm.fetch_recent_wallpapers());
+
+    first.unwrap();
+    second.unwrap();
+    assert_eq!(wm.get_cached_wallpapers().await.unwrap().len(), 1);
+    let saved_requests = reddit
+        .requests()
+        .iter()
+        .filter(|request| request.starts_with("/user/tester/saved"))
+        .count();
+    assert_eq!(saved_requests, 1);
+    assert!(wm.fetch_all_wallpapers().await.is_ok());
+}
+
+#[tokio::test]
+async fn fetch_recent_adds_duplicate_posts_once() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let a = reddit.add_image("a.png", "image/png", png());
+    reddit.set_saved(vec![
+        vec![link("t3_a", "wallpaper", &a)],
+        vec![link("t3_a", "wallpaper", &a)],
+    ]);
+
+    let wm = manager(&reddit, &dir).await;
+    wm.fetch_recent_wallpapers().await.unwrap();
+
+    assert_eq!(wm.get_cached_wallpapers().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn fetch_recent_filters_by_extension_and_subreddit() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let page = reddit.add_image("page.html", "text/html", b"<html></html>".to_vec());
+    let other = reddit.add_image("other.png", "image/png", png());
+    // the url claims jpg but the server sends a png
+    let mislabeled = reddit.add_image("mislabeled.jpg", "image/png", png());
+    reddit.set_saved(vec![vec![
+        link("t3_page", "wallpaper", &page),
+        link("t3_other", "pics", &other),
+        link("t3_mislabeled", "wallpaper", &mislabeled),
+    ]]);
+
+    let wm = manager(&reddit, &dir).await;
+    wm.fetch_recent_wallpapers().await.unwrap();
+
+    let wallpapers = wm.get_cached_wallpapers().await.unwrap();
+    assert_eq!(wallpapers.len(), 1);
+    assert_eq!(wallpapers[0].name, "t3_mislabeled");
+    assert_eq!(wallpapers[0].file_name, "t3_mislabeled.png");
+    assert!(dir.path().join("t3_mislabeled.png").is_file());
+}
+
+#[tokio::test]
+async fn fetch_recent_detects_the_format_from_the_content() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let error_page = reddit.add_image(
+        "error.jpg",
+        "image/jpeg",
+        b"<!doctype html><html><body>gone</body></html>".to_vec(),
+    );
+    let no_extension = reddit.add_image("plain", "application/octet-stream", png());
+    reddit.set_saved(vec![vec![
+        link("t3_error", "wallpaper", &error_page),
+        link("t3_plain", "wallpaper", &no_extension),
+    ]]);
+
+    let wm = manager(&reddit, &dir).await;
+    wm.fetch_recent_wallpapers().await.unwrap();
+
+    let wallpapers = wm.get_cached_wallpapers().await.unwrap();
+    assert_eq!(wallpapers.len(), 1);
+    assert_eq!(wallpapers[0].file_name, "t3_plain.png");
+    assert_eq!(wallpapers[0].format, "png");
+    assert!(!dir.path().join("t3_error.part").exists());
+
+    // rejected images are not downloaded again
+    reddit.reset_requests();
+    wm.fetch_recent_wallpapers().await.unwrap();
+    assert!(!reddit
+        .requests()
+        .iter()
+        .any(|request| request.starts_with("/images/")));
+}
+
+#[tokio::test]
+async fn fetch_recent_adds_every_gallery_item() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let first = reddit.add_image("first.png", "image/png", png());
+    let second = reddit.add_image("second.png", "image/png", png());
+    reddit.set_saved(vec![vec![gallery(
+        "t3_g",
+        "wallpaper",
+        &[
+            ("first", &format!("{first}?width=8&format=png")),
+            ("second", &second),
+        ],
+    )]]);
+
+    let wm = manager(&reddit, &dir).await;
+    wm.fetch_recent_wallpapers().await.unwrap();
+
+    let wallpapers = wm.get_cached_wallpapers().await.unwrap();
+    let mut file_names = wallpapers
+        .iter()
+        .map(|wp| wp.file_name.as_str())
+        .collect::<Vec<_>>();
+    file_names.sort_unstable();
+    assert_eq!(file_names, ["t3_g_first.png", "t3_g_second.png"]);
+    assert!(wallpapers.iter().all(|wp| wp.post_name == "t3_g"));
+    for file_name in file_names {
+        assert!(dir.path().join("thumbnails").join(file_name).is_file());
+    }
+}
+
+#[tokio::test]
+async fn fetch_recent_uses_configured_subreddits() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let earth = reddit.add_image("earth.png", "image/png", png());
+    let wide = reddit.add_image("wide.png", "image/png", png());
+    let pics = reddit.add_image("pics.png", "image/png", png());
+    reddit.set_saved(vec![vec![
+        link("t3_earth", "EarthPorn", &earth),
+        link("t3_wide", "WidescreenWallpaper", &wide),
+        link("t3_pics", "pics", &pics),
+    ]]);
+
+    let mut config = reddit.config(dir.path().to_owned());
+    config.subreddits = Subreddits(vec![
+        "earthporn".to_owned(),
+        "r/WidescreenWallpaper".to_owned(),
+    ]);
+    let wm = WallpaperManager::with_config(config).await;
+    wm.fetch_recent_wallpapers().await.unwrap();
+
+    let mut names = wm
+        .get_cached_wallpapers()
+        .await
+        .unwrap()
+        .iter()
+        .map(|wp| wp.name.clone())
+        .collect::<Vec<_>>();
+    names.sort_unstable();
+    assert_eq!(names, ["t3_earth", "t3_wide"]);
+}
+
+#[tokio::test]
+async fn fetch_recent_accepts_image_posts_of_any_subreddit() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let pics = reddit.add_image("pics.png", "image/png", png());
+    reddit.set_saved(vec![vec![
+        link("t3_pics", "pics", &pics),
+        link(
+            "t3_text",
+            "rust",
+            "https://www.reddit.com/r/rust/comments/abc/",
+        ),
+    ]]);
+
+    let mut config = reddit.config(dir.path().to_owned());
+    config.any_subreddit = true;
+    let wm = WallpaperManager::with_config(config).await;
+    wm.fetch_recent_wallpapers().await.unwrap();
+
+    let wallpapers = wm.get_cached_wallpapers().await.unwrap();
+    assert_eq!(wallpapers.len(), 1);
+    assert_eq!(wallpapers[0].name, "t3_pics");
+}
+
+#[tokio::test]
+async fn candidates_exclude_downloaded_and_non_image_posts() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let a = reddit.add_image("a.png", "image/png", png());
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", &a)]]);
+    reddit.set_listing(
+        "/r/wallpaper/new",
+        vec![vec![
+            link("t3_a", "wallpaper", &a),
+            link("t3_b", "wallpaper", "https://example.com/b.jpg"),
+            link(
+                "t3_text",
+                "wallpaper",
+                "https://www.reddit.com/r/wallpaper/comments/c/",
+            ),
+        ]],
+    );
+
+    let wm = manager(&reddit, &dir).await;
+    wm.fetch_recent_wallpapers().await.unwrap();
+    let source = ListingSource::Subreddit {
+        name: "wallpaper".to_owned(),
+        sort: Sort::New,
+    };
+    let page = wm.fetch_candidates(&source, None).await.unwrap();
+
+    let names = page
+        .posts
+        .iter()
+        .map(|post| post.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["t3_b"]);
+    assert_eq!(page.after, None);
+}
+
+#[tokio::test]
+async fn candidates_exclude_posts_of_a_reopened_library() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let database = dir.path().join("library.sqlite");
+    let cache = dir.path().join("cache.json");
+    let a = reddit.add_image("a.png", "image/png", png());
+    let b = reddit.add_image("b.png", "image/png", png());
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", &a)]]);
+    reddit.set_listing(
+        "/r/wallpaper/new",
+        vec![vec![
+            link("t3_a", "wallpaper", &a),
+            link("t3_b", "wallpaper", &b),
+        ]],
+    );
+    let wm = manager(&reddit, &dir)
+        .await
+        .with_library_file(&database, &cache);
+    wm.fetch_recent_wallpapers().await.unwrap();
+    drop(wm);
+
+    // the saved wallpapers are only in the database now
+    let wm = manager(&reddit, &dir)
+        .await
+        .with_library_file(&database, &cache);
+    let source = ListingSource::Subreddit {
+        name: "wallpaper".to_owned(),
+        sort: Sort::New,
+    };
+    let page = wm.fetch_candidates(&source, None).await.unwrap();
+    let names = page
+        .posts
+        .iter()
+        .map(|post| post.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["t3_b"]);
+}
+
+/// A manager whose credentials were protected with `passphrase` by an earlier run
+async fn locked_manager(reddit: &FakeReddit, dir: &TempDir, passphrase: &str) -> WallpaperManager {
+    let config_file = dir.path().join("wallpapers.toml");
+    let wm = manager(reddit, dir)
+        .await
+        .with_config_file(config_file.clone());
+    wm.set_credentials_passphrase(Some(passphrase.to_owned()))
+        .unwrap();
+    drop(wm);
+    let wm = manager(reddit, dir).await.with_config_file(config_file);
+    assert!(wm.credentials_locked());
+    wm
+}
+
+fn assert_locked<T: std::fmt::Debug>(result: Result<T, WallpaperError>) {
+    assert!(
+        matches!(
+            result,
+            Err(WallpaperError::Credentials(CredentialError::Locked))
+        ),
+        "{result:?}"
+    );
+}
+
+#[tokio::test]
+async fn locked_credentials_are_not_overwritten_by_the_config() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let wm = locked_manager(&reddit, &dir, "secret").await;
+
+    // the config carries a secret, as if the user typed one into the form
+    let config = wm.config.lock().unwrap().clone();
+    assert_locked(wm.set_config(config).await);
+
+    let store = CredentialStore::new(dir.path());
+    assert!(store.needs_passphrase().unwrap());
+    assert_eq!(store.load(Some("secret")).unwrap().password, "hunter2");
+    assert!(!dir.path().join("credentials.json.tmp").exists());
+}
+
+#[tokio::test]
+async fn profiles_cannot_change_while_the_credentials_are_locked() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let wm = locked_manager(&reddit, &dir, "secret").await;
+
+    assert_locked(wm.add_profile("work"));
+    assert_locked(wm.switch_profile("default").await);
+    assert_eq!(wm.profiles(), ["default"]);
+
+    let store = CredentialStore::new(dir.path());
+    assert!(store.needs_passphrase().unwrap());
+    assert_eq!(store.load(Some("secret")).unwrap().password, "hunter2");
+}
+
+#[tokio::test]
+async fn profile_changes_are_saved() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let config_file = dir.path().join("wallpapers.toml");
+    let wm = manager(&reddit, &dir)
+        .await
+        .with_config_file(config_file.clone());
+
+    wm.add_profile("work").unwrap();
+    let saved = std::fs::read_to_string(&config_file).unwrap();
+    assert!(saved.contains("active_profile = \"work\""));
+    assert!(saved.contains("[profiles.default]"));
+
+    wm.switch_profile("default").await.unwrap();
+    let saved = std::fs::read_to_string(&config_file).unwrap();
+    assert!(saved.contains("active_profile = \"default\""));
+    assert!(saved.contains("[profiles.work]"));
+    // the secrets stay in the credential store
+    assert!(!saved.contains("hunter2"));
+    let secrets = CredentialStore::new(dir.path()).load(None).unwrap();
+    assert_eq!(secrets.password, "hunter2");
+}
+
+#[tokio::test]
+async fn every_profile_fetches_from_its_own_cursor() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    let image = |name: &str| reddit.add_image(&format!("{name}.png"), "image/png", png());
+    let (a, b, z) = (image("a"), image("b"), image("z"));
+    reddit.set_saved(vec![vec![link("t3_a", "wallpaper", &a)]]);
+    let wm = manager(&reddit, &dir).await;
+    wm.fetch_recent_wallpapers().await.unwrap();
+
+    // the newer post is on the first page, the older one on the second
+    reddit.set_saved(vec![
+        vec![link("t3_b", "wallpaper", &b), link("t3_a", "wallpaper", &a)],
+        vec![link("t3_z", "wallpaper", &z)],
+    ]);
+    wm.add_profile("work").unwrap();
+    let mut config = wm.config.lock().unwrap().clone();
+    config.set_refresh_token(REFRESH_TOKEN.to_owned());
+    wm.set_config(config).await.unwrap();
+    reddit.reset_requests();
+    wm.fetch_recent_wallpapers().await.unwrap();
+    // the new profile has seen nothing yet and reads the whole listing
+    assert!(reddit
+        .requests()
+        .iter()
+        .any(|request| request.contains("after=page1")));
+    let work = wm
+        .get_cached_wallpapers()
+        .await
+        .unwrap()
+        .iter()
+        .filter(|wallpaper| wallpaper.profile == "work")
+        .map(|wallpaper| wallpaper.name.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(work, ["t3_b", "t3_z"]);
+
+    // the default profile stops at the post it saw last
+    wm.switch_profile("default").await.unwrap();
+    reddit.reset_requests();
+    wm.fetch_recent_wallpapers().await.unwrap();
+    assert!(reddit
+        .requests()
+        .iter()
+        .any(|request| request.starts_with("/user/tester/saved")));
+    assert!(!reddit
+        .requests()
+        .iter()
+        .any(|request| request.contains("after=page1")));
+}
+
+#[tokio::test]
+async fn leftover_plaintext_does_not_replace_a_passphrase_store() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    drop(locked_manager(&reddit, &dir, "correct horse").await);
+    let store = CredentialStore::new(dir.path());
+    let stored = store.load(Some("correct horse")).unwrap();
+
+    let config_file = dir.path().join("wallpapers.toml");
+    let leftover = Config::new(
+        USERNAME,
+        "old-password",
+        "client-id",
+        "old-secret",
+        dir.path().to_owned(),
+    );
+    std::fs::write(&config_file, toml::to_string(&leftover).unwrap()).unwrap();
+    let config = WallpaperManager::read_config(&config_file);
+
+    // the secrets stay locked, and the leftovers are gone from the config file
+    assert!(config.secrets().is_empty());
+    assert!(store.needs_passphrase().unwrap());
+    assert_eq!(store.load(Some("correct horse")).unwrap(), stored);
+    let written = std::fs::read_to_string(&config_file).unwrap();
+    assert!(!written.contains("old-password"));
+    assert!(!written.contains("old-secret"));
+}
+
+#[tokio::test]
+async fn unreadable_images_are_skipped_by_the_thumbnails() {
+    let reddit = FakeReddit::start().await;
+    let dir = TempDir::new().unwrap();
+    std::fs::write(dir.path().join("t3_a.png"), png()).unwrap();
+    std::fs::write(dir.path().join("t3_bad.png"), "truncated").unwrap();
+
+    let wm = manager(&reddit, &dir).await;
+    assert_eq!(wm.rescan_library(false).await.unwrap(), 2);
+    let thumbnails = dir.path().join("thumbnails");
+    assert!(thumbnails.join("t3_a.png").is_file());
+    assert!(!thumbnails.join("t3_bad.png").exists());
+}